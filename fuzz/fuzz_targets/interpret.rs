@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rsh::{ExitStatus, Interpreter, MockSpawner, Scripted};
+
+fuzz_target!(|input: &str| {
+    // Redirections create real files, so keep them inside a scratch directory.
+    if input.contains('/') || input.contains("..") {
+        return;
+    }
+    let scratch = std::env::temp_dir().join("rsh-fuzz");
+    let _ = std::fs::create_dir_all(&scratch);
+    // The mock runs no commands, and keeps `kill` from signalling the fuzzer or its neighbours.
+    let mock = MockSpawner::new();
+    mock.script("true", Scripted::default());
+    mock.script("false", Scripted { status: ExitStatus::FAILURE, ..Scripted::default() });
+    let mut interpreter = Interpreter::builder()
+        .env([("HOME", scratch.display())])
+        .cwd(&scratch)
+        .spawner(mock)
+        .build()
+        .expect("the scratch directory is usable");
+    let _ = interpreter.capture(input);
+});
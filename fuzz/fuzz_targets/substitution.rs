@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rsh::Environment;
+
+fuzz_target!(|input: &str| {
+    let mut env = Environment::new();
+    env.insert("a".to_string(), "x y".to_string());
+    env.insert("arr[1]".to_string(), "z".to_string());
+    env.insert("1".to_string(), "one".to_string());
+    let _ = rsh::fuzzing::expand(input, &mut env);
+});
@@ -0,0 +1,131 @@
+use crate::errors::InterpreterResult;
+use crate::lexer::{self, Token};
+use crate::substitution::quote;
+use std::collections::BTreeMap;
+
+/// Characters that may not appear in an alias name, because they quote, expand or separate
+/// words.
+const RESERVED: &[char] = &['/', '$', '`', '=', '\\', '\'', '"', '(', ')', '<', '>', '&', '|', ';', '#'];
+
+/// The aliases defined with `alias`. An alias replaces the command word of a simple command
+/// with its value when a line of input is compiled, so one defined on some line takes
+/// effect from the next line run.
+#[derive(Default)]
+pub struct Aliases {
+    aliases: BTreeMap<String, String>
+}
+
+impl Aliases {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.aliases.get(name).map(String::as_str)
+    }
+
+    /// Defines the alias `name`, returning false if `name` cannot be an alias.
+    pub fn set(&mut self, name: &str, value: &str) -> bool {
+        if !is_name(name) {
+            return false;
+        }
+        self.aliases.insert(name.to_string(), value.to_string());
+        true
+    }
+
+    /// Removes the alias `name`, returning false if there was none.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.aliases.remove(name).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.aliases.clear();
+    }
+
+    /// Every alias with its value, in order of name.
+    pub fn list(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.aliases.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Replaces each command word that names an alias with the tokens of its value. The
+    /// first word of a value is expanded in turn, except for an alias that is already being
+    /// expanded, and a value ending in a blank also has the word after it expanded. The
+    /// replacement tokens take the span of the word they replace.
+    pub fn expand(&self, tokens: Vec<Token>) -> InterpreterResult<Vec<Token>> {
+        if self.aliases.is_empty() {
+            return Ok(tokens);
+        }
+        let mut expanded = Vec::with_capacity(tokens.len());
+        self.expand_into(tokens, true, &mut vec![], &mut expanded)?;
+        Ok(expanded)
+    }
+
+    /// Expands `tokens` onto `expanded`, starting in command position if `command`, and
+    /// returns whether the word following them is in command position. `active` holds the
+    /// aliases being expanded.
+    fn expand_into(&self, tokens: Vec<Token>, mut command: bool, active: &mut Vec<String>, expanded: &mut Vec<Token>) -> InterpreterResult<bool> {
+        // Words inside `[[ ... ]]` are operands, even after `&&` or `||`.
+        let mut conditional = false;
+        for token in tokens {
+            let value = match self.aliases.get(&token.text) {
+                Some(value) if command && !conditional && !active.contains(&token.text) => value,
+                _ => {
+                    match token.text.as_str() {
+                        "[[" if command => conditional = true,
+                        "]]" => conditional = false,
+                        _ => {}
+                    }
+                    command = lexer::is_control_operator(&token.text) || lexer::is_pipeline_prefix(&token.text);
+                    expanded.push(token);
+                    continue;
+                }
+            };
+            let replacement = lexer::tokenize(value)?.into_iter().map(|replaced| Token{ span: token.span, ..replaced }).collect();
+            active.push(token.text.clone());
+            let next = self.expand_into(replacement, true, active, expanded)?;
+            active.pop();
+            command = next || value.ends_with([' ', '\t']);
+        }
+        Ok(command)
+    }
+}
+
+/// Whether `name` may be defined as an alias.
+pub fn is_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || RESERVED.contains(&c))
+}
+
+/// Formats an alias the way `alias` lists it, as a command that defines it again.
+pub fn format(name: &str, value: &str) -> String {
+    format!("alias {}={}", name, quote(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(aliases: &Aliases, input: &str) -> Vec<String> {
+        aliases.expand(lexer::tokenize(input).unwrap()).unwrap().into_iter().map(|token| token.text).collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut aliases = Aliases::default();
+        for (name, value) in [("ll", "ls -l"), ("ls", "ls -F"), ("a", "b"), ("b", "a x"), ("sudo", "sudo "), ("e", "")] {
+            assert!(aliases.set(name, value));
+        }
+        assert_eq!(expand(&aliases, "ll src; echo ll | ll"), vec!["ls", "-F", "-l", "src", ";", "echo", "ll", "|", "ls", "-F", "-l"]);
+        assert_eq!(expand(&aliases, "a"), vec!["a", "x"]);
+        assert_eq!(expand(&aliases, "sudo ll 'll' && ! e ll"), vec!["sudo", "ls", "-F", "-l", "'ll'", "&&", "!", "ls", "-F", "-l"]);
+        assert_eq!(expand(&aliases, "[[ a && ll ]]"), vec!["[[", "a", "&&", "ll", "]]"]);
+        let tokens = aliases.expand(lexer::tokenize("x; ll").unwrap()).unwrap();
+        assert!(tokens[2..].iter().all(|token| token.span == tokens[2].span));
+    }
+
+    #[test]
+    fn test_names() {
+        let mut aliases = Aliases::default();
+        assert!(!aliases.set("a=b", "c"));
+        assert!(!aliases.set("", "c"));
+        assert!(aliases.set("g.", "git"));
+        assert_eq!(format("g.", "it's"), "alias g.='it'\\''s'");
+        assert!(aliases.remove("g."));
+        assert!(!aliases.remove("g."));
+    }
+}
@@ -0,0 +1,534 @@
+use crate::Environment;
+use crate::errors::{InterpreterError, InterpreterResult};
+
+/// How deeply variables whose values are themselves expressions may be re-evaluated.
+const MAX_RECURSION: usize = 64;
+
+/// How deeply subexpressions may nest, which bounds the stack used to parse and evaluate them.
+const MAX_NESTING: usize = 128;
+
+/// Operators recognised by the tokenizer. Longer operators must come before their prefixes.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=",
+    "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "+=", "-=", "*=", "/=", "%=", "&=", "^=", "|=",
+    "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "|", "^", "?", ":", ",", "(", ")",
+];
+
+const ASSIGNMENTS: &[&str] = &["=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|="];
+
+/// Evaluates a 64 bit integer arithmetic expression using C operator precedence.
+/// Variables are referenced by name and assignments are written back into `env`.
+pub fn evaluate<S: AsRef<str>>(expression: S, env: &mut Environment) -> InterpreterResult<i64> {
+    evaluate_at(expression.as_ref(), env, 0)
+}
+
+fn evaluate_at(expression: &str, env: &mut Environment, depth: usize) -> InterpreterResult<i64> {
+    if depth > MAX_RECURSION {
+        return Err(InterpreterError::runtime(format!("{}: expression recursion level exceeded", expression)));
+    }
+    let tokens = tokenize(expression)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser { tokens, pos: 0, depth: 0 };
+    let expr = parser.comma()?;
+    if let Some(token) = parser.peek() {
+        return Err(syntax_error(expression, token));
+    }
+    expr.eval(env, depth)
+}
+
+fn syntax_error(expression: &str, token: &Token) -> InterpreterError {
+    InterpreterError::runtime(format!("{}: syntax error in expression (error token is \"{}\")", expression.trim(), token))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Name(name) => f.write_str(name),
+            Token::Operator(op) => f.write_str(op)
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> InterpreterResult<Vec<Token>> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '#' || chars[pos] == '_') {
+                pos += 1;
+            }
+            let literal: String = chars[start..pos].iter().collect();
+            tokens.push(Token::Number(number(&literal)?));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            tokens.push(Token::Name(chars[start..pos].iter().collect()));
+        } else {
+            let rest = &chars[pos..];
+            match OPERATORS.iter().find(|op| op.chars().enumerate().all(|(idx, c)| rest.get(idx) == Some(&c))) {
+                Some(op) => {
+                    tokens.push(Token::Operator(op));
+                    pos += op.len();
+                }
+                None => {
+                    let rest: String = rest.iter().collect();
+                    return Err(InterpreterError::runtime(format!("{}: syntax error: operand expected (error token is \"{}\")", expression.trim(), rest)));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses an integer literal: decimal, `0x` hexadecimal, leading `0` octal or `base#digits`.
+fn number(literal: &str) -> InterpreterResult<i64> {
+    let invalid = || InterpreterError::runtime(format!("{}: value too great for base (error token is \"{}\")", literal, literal));
+    let (base, digits) = if let Some(idx) = literal.find('#') {
+        let base = literal[..idx].parse::<u32>().map_err(|_| invalid())?;
+        if !(2..=64).contains(&base) {
+            return Err(InterpreterError::runtime(format!("{}: invalid arithmetic base", literal)));
+        }
+        (base, &literal[idx + 1..])
+    } else if literal.starts_with("0x") || literal.starts_with("0X") {
+        (16, &literal[2..])
+    } else if literal.len() > 1 && literal.starts_with('0') {
+        (8, &literal[1..])
+    } else {
+        (10, literal)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid())
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Assign(&'static str, String, Box<Expr>),
+    Increment { name: String, delta: i64, prefix: bool },
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Operator(op)) => Some(*op),
+            _ => None
+        }
+    }
+
+    fn expect(&mut self, operator: &'static str) -> InterpreterResult<()> {
+        if self.peek_operator() == Some(operator) {
+            self.pos += 1;
+            return Ok(());
+        }
+        Err(self.unexpected())
+    }
+
+    fn unexpected(&self) -> InterpreterError {
+        match self.peek() {
+            Some(token) => InterpreterError::runtime(format!("syntax error in expression (error token is \"{}\")", token)),
+            None => InterpreterError::runtime("syntax error: operand expected")
+        }
+    }
+
+    /// Parses a subexpression with `parse`, failing if expressions are nested too deeply.
+    fn nested<F: FnOnce(&mut Self) -> InterpreterResult<Expr>>(&mut self, parse: F) -> InterpreterResult<Expr> {
+        if self.depth >= MAX_NESTING {
+            return Err(InterpreterError::runtime("expression nested too deeply"));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn comma(&mut self) -> InterpreterResult<Expr> {
+        let mut expr = self.assignment()?;
+        while self.peek_operator() == Some(",") {
+            self.pos += 1;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(self.assignment()?));
+        }
+        Ok(expr)
+    }
+
+    fn assignment(&mut self) -> InterpreterResult<Expr> {
+        let lhs = self.conditional()?;
+        match self.peek_operator() {
+            Some(op) if ASSIGNMENTS.contains(&op) => {
+                self.pos += 1;
+                match lhs {
+                    Expr::Variable(name) => Ok(Expr::Assign(op, name, Box::new(self.nested(Self::assignment)?))),
+                    _ => Err(InterpreterError::runtime("attempted assignment to non-variable"))
+                }
+            }
+            _ => Ok(lhs)
+        }
+    }
+
+    fn conditional(&mut self) -> InterpreterResult<Expr> {
+        let condition = self.binary(0)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.nested(Self::assignment)?;
+        self.expect(":")?;
+        let otherwise = self.nested(Self::conditional)?;
+        Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> InterpreterResult<Expr> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_operator() {
+            let precedence = match precedence(op) {
+                Some(precedence) if precedence >= min_precedence => precedence,
+                _ => break
+            };
+            self.pos += 1;
+            // `**` is the only right associative binary operator.
+            let next = if op == "**" { precedence } else { precedence + 1 };
+            let rhs = self.nested(|parser| parser.binary(next))?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> InterpreterResult<Expr> {
+        match self.peek_operator() {
+            Some(op @ "++") | Some(op @ "--") => {
+                self.pos += 1;
+                match self.peek().cloned() {
+                    Some(Token::Name(name)) => {
+                        self.pos += 1;
+                        Ok(Expr::Increment { name, delta: if op == "++" { 1 } else { -1 }, prefix: true })
+                    }
+                    // `--5` is a double negation rather than a decrement.
+                    _ => {
+                        let sign = if op == "++" { "+" } else { "-" };
+                        Ok(Expr::Unary(sign, Box::new(Expr::Unary(sign, Box::new(self.nested(Self::unary)?)))))
+                    }
+                }
+            }
+            Some(op @ "-") | Some(op @ "+") | Some(op @ "!") | Some(op @ "~") => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.nested(Self::unary)?)))
+            }
+            _ => self.postfix()
+        }
+    }
+
+    fn postfix(&mut self) -> InterpreterResult<Expr> {
+        let expr = self.primary()?;
+        if let Expr::Variable(name) = &expr {
+            match self.peek_operator() {
+                Some("++") => {
+                    self.pos += 1;
+                    return Ok(Expr::Increment { name: name.clone(), delta: 1, prefix: false });
+                }
+                Some("--") => {
+                    self.pos += 1;
+                    return Ok(Expr::Increment { name: name.clone(), delta: -1, prefix: false });
+                }
+                _ => {}
+            }
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> InterpreterResult<Expr> {
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok(Expr::Number(n))
+            }
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                Ok(Expr::Variable(name))
+            }
+            Some(Token::Operator("(")) => {
+                self.pos += 1;
+                let expr = self.nested(Self::comma)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            _ => Err(self.unexpected())
+        }
+    }
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "|" => Some(3),
+        "^" => Some(4),
+        "&" => Some(5),
+        "==" | "!=" => Some(6),
+        "<" | ">" | "<=" | ">=" => Some(7),
+        "<<" | ">>" => Some(8),
+        "+" | "-" => Some(9),
+        "*" | "/" | "%" => Some(10),
+        "**" => Some(11),
+        _ => None
+    }
+}
+
+impl Expr {
+    fn eval(&self, env: &mut Environment, depth: usize) -> InterpreterResult<i64> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Variable(name) => variable(name, env, depth),
+            Expr::Unary(op, operand) => {
+                let value = operand.eval(env, depth)?;
+                Ok(match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value
+                })
+            }
+            Expr::Binary("&&", lhs, rhs) => {
+                Ok((lhs.eval(env, depth)? != 0 && rhs.eval(env, depth)? != 0) as i64)
+            }
+            Expr::Binary("||", lhs, rhs) => {
+                Ok((lhs.eval(env, depth)? != 0 || rhs.eval(env, depth)? != 0) as i64)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(env, depth)?;
+                let rhs = rhs.eval(env, depth)?;
+                apply(op, lhs, rhs)
+            }
+            Expr::Assign(op, name, rhs) => {
+                let rhs = rhs.eval(env, depth)?;
+                let value = match *op {
+                    "=" => rhs,
+                    op => apply(&op[..op.len() - 1], variable(name, env, depth)?, rhs)?
+                };
+                env.insert(name.clone(), value.to_string());
+                Ok(value)
+            }
+            Expr::Increment { name, delta, prefix } => {
+                let old = variable(name, env, depth)?;
+                let new = old.wrapping_add(*delta);
+                env.insert(name.clone(), new.to_string());
+                Ok(if *prefix { new } else { old })
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if condition.eval(env, depth)? != 0 {
+                    then.eval(env, depth)
+                } else {
+                    otherwise.eval(env, depth)
+                }
+            }
+        }
+    }
+}
+
+/// Resolves a variable for arithmetic. Unset and empty variables are zero, and values
+/// that are not plain integers are evaluated as expressions in their own right.
+fn variable(name: &str, env: &mut Environment, depth: usize) -> InterpreterResult<i64> {
+    let value = match env.get(name) {
+        None => return Ok(0),
+        Some(value) if value.trim().is_empty() => return Ok(0),
+        Some(value) => value.clone()
+    };
+    match value.trim().parse::<i64>() {
+        Ok(n) => Ok(n),
+        Err(_) => evaluate_at(&value, env, depth + 1)
+    }
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> InterpreterResult<i64> {
+    Ok(match op {
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err(InterpreterError::runtime("division by 0")),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        "**" => {
+            if rhs < 0 {
+                return Err(InterpreterError::runtime("exponent less than 0"));
+            }
+            let (mut base, mut exponent, mut result) = (lhs, rhs, 1i64);
+            while exponent > 0 {
+                if exponent & 1 == 1 {
+                    result = result.wrapping_mul(base);
+                }
+                base = base.wrapping_mul(base);
+                exponent >>= 1;
+            }
+            result
+        }
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "&" => lhs & rhs,
+        "^" => lhs ^ rhs,
+        "|" => lhs | rhs,
+        "," => rhs,
+        _ => return Err(InterpreterError::runtime(format!("{}: unsupported operator", op)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> i64 {
+        evaluate(expression, &mut Environment::default()).unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("1 << 2 + 1"), 8);
+        assert_eq!(eval("1 | 2 ^ 3 & 4"), 3);
+        assert_eq!(eval("2 ** 3 ** 2"), 512);
+        assert_eq!(eval("-2 ** 2"), 4);
+        assert_eq!(eval("10 - 4 - 3"), 3);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(eval("7 / 2"), 3);
+        assert_eq!(eval("-7 % 3"), -1);
+        assert_eq!(eval("~0"), -1);
+        assert_eq!(eval("!5"), 0);
+        assert_eq!(eval("3 > 2 && 2 >= 2"), 1);
+        assert_eq!(eval("0 || 1 != 1"), 0);
+        assert_eq!(eval("1 ? 2 : 3"), 2);
+        assert_eq!(eval("0 ? 2 : 0 ? 3 : 4"), 4);
+        assert_eq!(eval("1, 2"), 2);
+        assert_eq!(eval(""), 0);
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(eval("0x1F"), 31);
+        assert_eq!(eval("010"), 8);
+        assert_eq!(eval("2#101"), 5);
+        assert!(evaluate("08", &mut Environment::default()).is_err());
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut env = Environment::default();
+        env.insert("x".to_string(), "5".to_string());
+        env.insert("y".to_string(), "x * 2".to_string());
+        assert_eq!(evaluate("x + y + unset", &mut env).unwrap(), 15);
+    }
+
+    #[test]
+    fn test_assignment() {
+        let mut env = Environment::default();
+        assert_eq!(evaluate("x = 3, x += 4, x <<= 1", &mut env).unwrap(), 14);
+        assert_eq!(env.get("x").unwrap(), "14");
+        assert_eq!(evaluate("a = b = 2", &mut env).unwrap(), 2);
+        assert_eq!(env.get("a").unwrap(), "2");
+        assert!(evaluate("1 = 2", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_increment() {
+        let mut env = Environment::default();
+        assert_eq!(evaluate("i++", &mut env).unwrap(), 0);
+        assert_eq!(evaluate("++i", &mut env).unwrap(), 2);
+        assert_eq!(evaluate("i--", &mut env).unwrap(), 2);
+        assert_eq!(env.get("i").unwrap(), "1");
+        assert_eq!(evaluate("--5", &mut env).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let mut env = Environment::default();
+        assert_eq!(evaluate("0 && x++", &mut env).unwrap(), 0);
+        assert_eq!(evaluate("1 || x++", &mut env).unwrap(), 1);
+        assert_eq!(evaluate("1 ? y : x++", &mut env).unwrap(), 0);
+        assert!(!env.contains_key("x"));
+        assert_eq!(evaluate("0 && 1 / 0", &mut env).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let mut env = Environment::default();
+        assert_eq!(evaluate("1 / 0", &mut env).unwrap_err().to_string(), "division by 0");
+        assert!(evaluate("x %= 0", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let mut env = Environment::default();
+        assert!(evaluate("1 +", &mut env).is_err());
+        assert!(evaluate("(1", &mut env).is_err());
+        assert!(evaluate("1 2", &mut env).is_err());
+        assert!(evaluate("$x", &mut env).is_err());
+    }
+
+    #[test]
+    fn test_nesting() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&nested(100)), 1);
+        assert!(evaluate(nested(100_000), &mut Environment::default()).is_err());
+        assert!(evaluate("-".repeat(100_000) + "1", &mut Environment::default()).is_err());
+        assert!(evaluate("2".to_string() + &"**2".repeat(100_000), &mut Environment::default()).is_err());
+        let mut env = Environment::default();
+        env.insert("x".to_string(), format!("{}x{}", "(".repeat(120), ")".repeat(120)));
+        assert!(evaluate("x", &mut env).is_err());
+    }
+}
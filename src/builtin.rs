@@ -0,0 +1,27 @@
+use crate::{Environment, ExitStatus};
+use std::io::{Read, Write};
+
+/// A command implemented by the host application that runs inside the interpreter.
+/// Builtins are looked up by name after expansion, ahead of `PATH`, and take part in
+/// pipelines and redirections like any other command. A builtin piped into another command
+/// runs in a forked copy of the process, where only the interpreter's thread exists, so it
+/// must not wait on locks or threads of the host there.
+pub trait Builtin {
+    /// Runs the builtin. `args` is the expanded command line, starting with the name the
+    /// builtin was invoked as.
+    fn run(&mut self, args: &[String], env: &mut Environment, stdio: &mut BuiltinStdio) -> ExitStatus;
+}
+
+impl <F: FnMut(&[String], &mut Environment, &mut BuiltinStdio) -> ExitStatus> Builtin for F {
+    fn run(&mut self, args: &[String], env: &mut Environment, stdio: &mut BuiltinStdio) -> ExitStatus {
+        self(args, env, stdio)
+    }
+}
+
+/// The standard streams of a running builtin, already connected to any pipes or
+/// redirections.
+pub struct BuiltinStdio<'a> {
+    pub stdin: &'a mut dyn Read,
+    pub stdout: &'a mut dyn Write,
+    pub stderr: &'a mut dyn Write
+}
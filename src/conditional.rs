@@ -0,0 +1,434 @@
+use crate::Environment;
+use crate::arithmetic;
+use crate::errors::{InterpreterError, InterpreterResult};
+use crate::pattern;
+use crate::physical;
+use crate::substitution::{escaped_substitution, set_array, substitution, Context};
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+const UNARY: &[&str] = &[
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-G", "-h", "-k", "-L", "-n", "-N", "-O", "-p",
+    "-r", "-s", "-S", "-t", "-u", "-w", "-x", "-z",
+];
+
+const BINARY: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+/// Evaluates the arguments of `test` (or `[`, without the closing bracket) after expansion.
+/// Relative file operands are resolved against `cwd`.
+pub fn test<S: AsRef<str>>(args: &[S], cwd: &Path) -> InterpreterResult<bool> {
+    let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+    // POSIX fixes the meaning of short argument lists regardless of what looks like an operator.
+    match args.as_slice() {
+        [] => return Ok(false),
+        [arg] => return Ok(!arg.is_empty()),
+        ["!", arg] => return Ok(arg.is_empty()),
+        [lhs, op, rhs] if BINARY.contains(op) || *op == "-a" || *op == "-o" => {
+            return match *op {
+                "-a" => Ok(!lhs.is_empty() && !rhs.is_empty()),
+                "-o" => Ok(!lhs.is_empty() || !rhs.is_empty()),
+                op => binary(op, lhs, rhs, cwd, integer)
+            }
+        }
+        ["!", rest @ ..] if rest.len() == 3 => return test(rest, cwd).map(|result| !result),
+        ["(", inner @ .., ")"] if inner.len() <= 2 => return test(inner, cwd),
+        _ => {}
+    }
+    let mut parser = TestParser { args: &args, cwd, pos: 0, depth: 0 };
+    let result = parser.or()?;
+    match parser.args.get(parser.pos) {
+        None => Ok(result),
+        Some(arg) => Err(InterpreterError::runtime(format!("test: {}: unexpected argument", arg)))
+    }
+}
+
+struct TestParser<'a> {
+    args: &'a [&'a str],
+    cwd: &'a Path,
+    pos: usize,
+    depth: usize
+}
+
+impl <'a> TestParser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.args.get(self.pos + offset).copied()
+    }
+
+    fn next(&mut self) -> InterpreterResult<&'a str> {
+        let arg = self.peek(0).ok_or_else(|| InterpreterError::runtime("test: argument expected"))?;
+        self.pos += 1;
+        Ok(arg)
+    }
+
+    /// Parses a nested expression with `parse`, failing if expressions are nested too deeply.
+    fn nested<F: FnOnce(&mut Self) -> InterpreterResult<bool>>(&mut self, parse: F) -> InterpreterResult<bool> {
+        if self.depth >= MAX_NESTING {
+            return Err(InterpreterError::runtime("test: expression nested too deeply"));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn or(&mut self) -> InterpreterResult<bool> {
+        let mut result = self.and()?;
+        while self.peek(0) == Some("-o") {
+            self.pos += 1;
+            let rhs = self.and()?;
+            result = result || rhs;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> InterpreterResult<bool> {
+        let mut result = self.not()?;
+        while self.peek(0) == Some("-a") {
+            self.pos += 1;
+            let rhs = self.not()?;
+            result = result && rhs;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> InterpreterResult<bool> {
+        if self.peek(0) == Some("!") && self.peek(1).is_some() {
+            self.pos += 1;
+            return self.nested(Self::not).map(|result| !result);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> InterpreterResult<bool> {
+        if self.peek(0) == Some("(") && self.peek(1).is_some() {
+            self.pos += 1;
+            let result = self.nested(Self::or)?;
+            if self.next()? != ")" {
+                return Err(InterpreterError::runtime("test: `)' expected"));
+            }
+            return Ok(result);
+        }
+        let arg = self.next()?;
+        if let Some(op) = self.peek(0) {
+            if BINARY.contains(&op) {
+                self.pos += 1;
+                let rhs = self.next()?;
+                return binary(op, arg, rhs, self.cwd, integer);
+            }
+        }
+        if UNARY.contains(&arg) {
+            if let Some(operand) = self.peek(0) {
+                self.pos += 1;
+                return unary(arg, operand, self.cwd);
+            }
+        }
+        Ok(!arg.is_empty())
+    }
+}
+
+fn integer(s: &str) -> InterpreterResult<i64> {
+    s.trim().parse::<i64>().map_err(|_| InterpreterError::runtime(format!("test: {}: integer expression expected", s)))
+}
+
+/// Evaluates a unary primary shared by `test` and `[[`.
+fn unary(op: &str, operand: &str, cwd: &Path) -> InterpreterResult<bool> {
+    let path = physical::resolve(cwd, operand);
+    let metadata = || fs::metadata(&path).ok();
+    Ok(match op {
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-a" | "-e" => metadata().is_some(),
+        "-f" => metadata().is_some_and(|m| m.is_file()),
+        "-d" => metadata().is_some_and(|m| m.is_dir()),
+        "-b" => metadata().is_some_and(|m| m.file_type().is_block_device()),
+        "-c" => metadata().is_some_and(|m| m.file_type().is_char_device()),
+        "-p" => metadata().is_some_and(|m| m.file_type().is_fifo()),
+        "-S" => metadata().is_some_and(|m| m.file_type().is_socket()),
+        "-h" | "-L" => fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()),
+        "-s" => metadata().is_some_and(|m| m.len() > 0),
+        "-g" => metadata().is_some_and(|m| m.permissions().mode() & 0o2000 != 0),
+        "-u" => metadata().is_some_and(|m| m.permissions().mode() & 0o4000 != 0),
+        "-k" => metadata().is_some_and(|m| m.permissions().mode() & 0o1000 != 0),
+        "-O" => metadata().is_some_and(|m| m.uid() == unsafe { libc::geteuid() }),
+        "-G" => metadata().is_some_and(|m| m.gid() == unsafe { libc::getegid() }),
+        "-N" => metadata().is_some_and(|m| m.mtime() > m.atime()),
+        "-r" => physical::access(&path, libc::R_OK),
+        "-w" => physical::access(&path, libc::W_OK),
+        "-x" => physical::access(&path, libc::X_OK),
+        "-t" => integer(operand).map(|fd| unsafe { libc::isatty(fd as libc::c_int) } == 1)?,
+        _ => return Err(InterpreterError::runtime(format!("{}: unary operator expected", op)))
+    })
+}
+
+/// Evaluates a binary primary shared by `test` and `[[`. Integer operands are converted
+/// with `integer`, since `[[` evaluates them arithmetically while `test` does not.
+fn binary<F: FnMut(&str) -> InterpreterResult<i64>>(op: &str, lhs: &str, rhs: &str, cwd: &Path, mut integer: F) -> InterpreterResult<bool> {
+    let modified = |path: &str| fs::metadata(physical::resolve(cwd, path)).and_then(|m| m.modified()).ok();
+    Ok(match op {
+        "=" | "==" => lhs == rhs,
+        "!=" => lhs != rhs,
+        "<" => lhs < rhs,
+        ">" => lhs > rhs,
+        "-eq" => integer(lhs)? == integer(rhs)?,
+        "-ne" => integer(lhs)? != integer(rhs)?,
+        "-lt" => integer(lhs)? < integer(rhs)?,
+        "-le" => integer(lhs)? <= integer(rhs)?,
+        "-gt" => integer(lhs)? > integer(rhs)?,
+        "-ge" => integer(lhs)? >= integer(rhs)?,
+        "-nt" => match (modified(lhs), modified(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs > rhs,
+            (lhs, rhs) => lhs.is_some() && rhs.is_none()
+        },
+        "-ot" => match (modified(lhs), modified(rhs)) {
+            (Some(lhs), Some(rhs)) => lhs < rhs,
+            (lhs, rhs) => lhs.is_none() && rhs.is_some()
+        },
+        "-ef" => physical::same_file(physical::resolve(cwd, lhs), physical::resolve(cwd, rhs)),
+        _ => return Err(InterpreterError::runtime(format!("{}: binary operator expected", op)))
+    })
+}
+
+/// The parsed body of a `[[ ... ]]` command. Operands are kept as raw words and only
+/// expanded during evaluation, without word splitting.
+pub enum Condition {
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Unary(String, String),
+    Binary(String, String, String),
+    Word(String),
+}
+
+impl Condition {
+    pub fn parse<S: AsRef<str>>(tokens: &[S]) -> InterpreterResult<Condition> {
+        let tokens: Vec<&str> = tokens.iter().map(AsRef::as_ref).collect();
+        if tokens.is_empty() {
+            return Err(InterpreterError::syntax("syntax error: expression expected after `[['"));
+        }
+        let mut parser = ConditionParser { tokens: &tokens, pos: 0, depth: 0 };
+        let condition = parser.or()?;
+        match parser.peek(0) {
+            None => Ok(condition),
+            Some(token) => Err(InterpreterError::syntax(format!("syntax error in conditional expression near `{}'", token)))
+        }
+    }
+
+    pub fn eval(&self, env: &mut Environment, context: Context, cwd: &Path) -> InterpreterResult<bool> {
+        match self {
+            Condition::Not(condition) => Ok(!condition.eval(env, context, cwd)?),
+            Condition::And(lhs, rhs) => Ok(lhs.eval(env, context, cwd)? && rhs.eval(env, context, cwd)?),
+            Condition::Or(lhs, rhs) => Ok(lhs.eval(env, context, cwd)? || rhs.eval(env, context, cwd)?),
+            Condition::Word(word) => Ok(!substitution(word, env, context)?.is_empty()),
+            Condition::Unary(op, operand) => unary(op, &substitution(operand, env, context)?, cwd),
+            Condition::Binary(op, lhs, rhs) => {
+                let lhs = substitution(lhs, env, context)?;
+                match op.as_str() {
+                    "=" | "==" => Ok(pattern::matches(escaped_substitution(rhs, env, context, pattern::escape)?, lhs)),
+                    "!=" => Ok(!pattern::matches(escaped_substitution(rhs, env, context, pattern::escape)?, lhs)),
+                    "=~" => rematch(&lhs, &escaped_substitution(rhs, env, context, regex::escape)?, env),
+                    op => {
+                        let rhs = substitution(rhs, env, context)?;
+                        binary(op, &lhs, &rhs, cwd, |operand| arithmetic::evaluate(operand, env))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Matches `text` against an extended regular expression, recording the match and its
+/// capture groups in `BASH_REMATCH`.
+fn rematch(text: &str, expression: &str, env: &mut Environment) -> InterpreterResult<bool> {
+    let regex = regex::Regex::new(expression).map_err(|err| InterpreterError::runtime(format!("{}: {}", expression, err)))?;
+    let groups: Vec<String> = match regex.captures(text) {
+        Some(captures) => captures.iter().map(|group| group.map_or("", |m| m.as_str()).to_string()).collect(),
+        None => vec![]
+    };
+    set_array("BASH_REMATCH", &groups, env);
+    Ok(!groups.is_empty())
+}
+
+/// How deeply `!` and parentheses may nest within `[[ ... ]]` or the arguments of `test`.
+const MAX_NESTING: usize = 256;
+
+struct ConditionParser<'a> {
+    tokens: &'a [&'a str],
+    pos: usize,
+    depth: usize
+}
+
+impl <'a> ConditionParser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.tokens.get(self.pos + offset).copied()
+    }
+
+    fn next(&mut self) -> InterpreterResult<&'a str> {
+        let token = self.peek(0).ok_or_else(|| InterpreterError::syntax("unexpected EOF while looking for `]]'"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Parses a nested condition with `parse`, failing if conditions are nested too deeply.
+    fn nested<F: FnOnce(&mut Self) -> InterpreterResult<Condition>>(&mut self, parse: F) -> InterpreterResult<Condition> {
+        if self.depth >= MAX_NESTING {
+            return Err(InterpreterError::syntax("syntax error in conditional expression: nested too deeply"));
+        }
+        self.depth += 1;
+        let condition = parse(self);
+        self.depth -= 1;
+        condition
+    }
+
+    fn or(&mut self) -> InterpreterResult<Condition> {
+        let mut condition = self.and()?;
+        while self.peek(0) == Some("||") {
+            self.pos += 1;
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> InterpreterResult<Condition> {
+        let mut condition = self.not()?;
+        while self.peek(0) == Some("&&") {
+            self.pos += 1;
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> InterpreterResult<Condition> {
+        if self.peek(0) == Some("!") {
+            self.pos += 1;
+            return Ok(Condition::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> InterpreterResult<Condition> {
+        let token = self.next()?;
+        match token {
+            "(" => {
+                let condition = self.nested(Self::or)?;
+                if self.next()? != ")" {
+                    return Err(InterpreterError::syntax("syntax error in conditional expression: expected `)'"));
+                }
+                Ok(condition)
+            }
+            "&&" | "||" | ")" => Err(InterpreterError::syntax(format!("syntax error in conditional expression near `{}'", token))),
+            _ => {
+                if let Some(op) = self.peek(0) {
+                    if BINARY.contains(&op) || op == "=~" {
+                        self.pos += 1;
+                        let rhs = self.next()?;
+                        return Ok(Condition::Binary(op.to_string(), token.to_string(), rhs.to_string()));
+                    }
+                }
+                match self.peek(0) {
+                    Some(operand) if UNARY.contains(&token) && !["&&", "||", ")"].contains(&operand) => {
+                        self.pos += 1;
+                        Ok(Condition::Unary(token.to_string(), operand.to_string()))
+                    }
+                    _ => Ok(Condition::Word(token.to_string()))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cond(tokens: &[&str], env: &mut Environment) -> bool {
+        Condition::parse(tokens).unwrap().eval(env, Context::default(), Path::new(".")).unwrap()
+    }
+
+    #[test]
+    fn test_short_forms() {
+        assert!(!test::<&str>(&[], Path::new(".")).unwrap());
+        assert!(test(&["-n"], Path::new(".")).unwrap());
+        assert!(!test(&[""], Path::new(".")).unwrap());
+        assert!(test(&["!", ""], Path::new(".")).unwrap());
+        assert!(test(&["=", "=", "="], Path::new(".")).unwrap());
+        assert!(!test(&["!", "a", "=", "a"], Path::new(".")).unwrap());
+        assert!(test(&["(", "x", ")"], Path::new(".")).unwrap());
+    }
+
+    #[test]
+    fn test_strings_and_integers() {
+        assert!(test(&["abc", "!=", "abd"], Path::new(".")).unwrap());
+        assert!(test(&["-z", ""], Path::new(".")).unwrap());
+        assert!(test(&["10", "-gt", "9"], Path::new(".")).unwrap());
+        assert!(!test(&["10", "-lt", "9"], Path::new(".")).unwrap());
+        assert!(test(&["x", "-eq", "1"], Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn test_connectives() {
+        assert!(test(&["a", "-a", "b", "-o", ""], Path::new(".")).unwrap());
+        assert!(!test(&["", "-a", "b", "-o", ""], Path::new(".")).unwrap());
+        assert!(test(&["!", "-z", "a", "-a", "(", "1", "-eq", "1", ")"], Path::new(".")).unwrap());
+        assert!(test(&vec!["!"; 200_000].into_iter().chain(["a"]).collect::<Vec<&str>>(), Path::new(".")).is_err());
+        let nested: Vec<&str> = vec!["("; 100_000].into_iter().chain(["a"]).chain(vec![")"; 100_000]).collect();
+        assert!(test(&nested, Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn test_files() {
+        assert!(test(&["-d", "src"], Path::new(".")).unwrap());
+        assert!(test(&["-f", "Cargo.toml"], Path::new(".")).unwrap());
+        assert!(test(&["-s", "Cargo.toml"], Path::new(".")).unwrap());
+        assert!(test(&["-r", "Cargo.toml"], Path::new(".")).unwrap());
+        assert!(!test(&["-e", "does/not/exist"], Path::new(".")).unwrap());
+        assert!(!test(&["-L", "Cargo.toml"], Path::new(".")).unwrap());
+        assert!(test(&["Cargo.toml", "-ef", "./Cargo.toml"], Path::new(".")).unwrap());
+    }
+
+    #[test]
+    fn test_conditional_patterns() {
+        let mut env = Environment::default();
+        env.insert("file".to_string(), "main.rs".to_string());
+        assert!(cond(&["$file", "==", "*.rs"], &mut env));
+        assert!(!cond(&["$file", "==", "\"*.rs\""], &mut env));
+        assert!(cond(&["$file", "!=", "*.py", "&&", "-n", "$file"], &mut env));
+        assert!(cond(&["!", "(", "-z", "$file", "||", "$file", "<", "a", ")"], &mut env));
+    }
+
+    #[test]
+    fn test_conditional_no_word_splitting() {
+        let mut env = Environment::default();
+        env.insert("words".to_string(), "a b".to_string());
+        assert!(cond(&["$words", "==", "'a b'"], &mut env));
+        assert!(cond(&["-n", "$unset", "||", "-z", "$unset"], &mut env));
+    }
+
+    #[test]
+    fn test_conditional_regex() {
+        let mut env = Environment::default();
+        env.insert("version".to_string(), "rsh-1.42".to_string());
+        assert!(cond(&["$version", "=~", "^([a-z]+)-([0-9]+)\\.([0-9]+)$"], &mut env));
+        assert_eq!(substitution("${BASH_REMATCH[0]} ${BASH_REMATCH[2]} ${BASH_REMATCH[3]}", &mut env, Context::default()).unwrap(), "rsh-1.42 1 42");
+        assert!(!cond(&["$version", "=~", "'.'5"], &mut env));
+        assert!(!env.contains_key("BASH_REMATCH"));
+    }
+
+    #[test]
+    fn test_conditional_arithmetic() {
+        let mut env = Environment::default();
+        env.insert("x".to_string(), "3".to_string());
+        assert!(cond(&["x + 1", "-eq", "4"], &mut env));
+    }
+
+    #[test]
+    fn test_conditional_syntax_errors() {
+        assert!(Condition::parse::<&str>(&[]).is_err());
+        assert!(Condition::parse(&["(", "a"]).is_err());
+        assert!(Condition::parse(&["a", "&&"]).is_err());
+        assert!(Condition::parse(&["a", "b"]).is_err());
+        assert!(Condition::parse(&vec!["!"; 100_000]).is_err());
+    }
+}
@@ -1,26 +1,173 @@
 use std::fmt::Formatter;
 use std::error::Error;
+use std::io;
+use crate::physical::strerror;
 
-pub struct InterpreterError {
-    pub(crate) message: String
+/// A range of bytes in the input given to the interpreter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span{start, end}
+    }
+
+    /// Returns the smallest span covering both spans.
+    pub fn join(self, other: Span) -> Span {
+        Span{start: self.start.min(other.start), end: self.end.max(other.end)}
+    }
+}
+
+/// Why the interpreter could not run some input. Each error records the span of input it
+/// arose from, where that is known, and the exit status the shell reports for it.
+pub enum InterpreterError {
+    Syntax { message: String, span: Option<Span> },
+    CommandNotFound { command: String, span: Option<Span> },
+    PermissionDenied { command: String, span: Option<Span> },
+    BadSubstitution { message: String, span: Option<Span> },
+    Redirection { target: String, error: io::Error, span: Option<Span> },
+    UnboundVariable { name: String, span: Option<Span> },
+    Io { error: io::Error, span: Option<Span> },
+    /// A builtin, arithmetic expression or other command failed.
+    Runtime { message: String, span: Option<Span> }
+}
+
+impl InterpreterError {
+    pub fn syntax<S: ToString>(message: S) -> InterpreterError {
+        InterpreterError::Syntax{message: message.to_string(), span: None}
+    }
+
+    pub fn bad_substitution<S: ToString>(message: S) -> InterpreterError {
+        InterpreterError::BadSubstitution{message: message.to_string(), span: None}
+    }
+
+    pub fn runtime<S: ToString>(message: S) -> InterpreterError {
+        InterpreterError::Runtime{message: message.to_string(), span: None}
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            InterpreterError::Syntax{span, ..} |
+            InterpreterError::CommandNotFound{span, ..} |
+            InterpreterError::PermissionDenied{span, ..} |
+            InterpreterError::BadSubstitution{span, ..} |
+            InterpreterError::Redirection{span, ..} |
+            InterpreterError::UnboundVariable{span, ..} |
+            InterpreterError::Io{span, ..} |
+            InterpreterError::Runtime{span, ..} => *span
+        }
+    }
+
+    /// Attributes the error to `span`, unless a more precise span is already known.
+    pub fn or_span(mut self, outer: Span) -> InterpreterError {
+        match &mut self {
+            InterpreterError::Syntax{span, ..} |
+            InterpreterError::CommandNotFound{span, ..} |
+            InterpreterError::PermissionDenied{span, ..} |
+            InterpreterError::BadSubstitution{span, ..} |
+            InterpreterError::Redirection{span, ..} |
+            InterpreterError::UnboundVariable{span, ..} |
+            InterpreterError::Io{span, ..} |
+            InterpreterError::Runtime{span, ..} => { span.get_or_insert(outer); }
+        }
+        self
+    }
+
+    /// The conventional exit status for the error: 2 for misuse of shell syntax, 126 for a
+    /// command that cannot be executed, 127 for one that cannot be found and 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InterpreterError::Syntax{..} => 2,
+            InterpreterError::PermissionDenied{..} => 126,
+            InterpreterError::CommandNotFound{..} => 127,
+            _ => 1
+        }
+    }
+
+    /// Formats the error followed by the line of `input` it arose from, with the offending
+    /// span underlined by carets.
+    pub fn diagnostic(&self, input: &str) -> String {
+        let span = match self.span() {
+            Some(span) if span.start <= input.len() => span,
+            _ => return self.to_string()
+        };
+        let start = input[..span.start].rfind('\n').map_or(0, |idx| idx + 1);
+        let end = input[span.start..].find('\n').map_or(input.len(), |idx| span.start + idx);
+        let line = &input[start..end];
+        let column = input[start..span.start].chars().count();
+        let width = input[span.start..span.end.clamp(span.start, end)].chars().count().max(1);
+        format!("{}\n  {}\n  {}{}", self, line, " ".repeat(column), "^".repeat(width))
+    }
+
+    /// Formats the error prefixed with the name of the file `input` was read from and the
+    /// number of the line it arose on.
+    pub fn located(&self, name: &str, input: &str) -> String {
+        match self.span() {
+            Some(span) if span.start <= input.len() => format!("{}: line {}: {}", name, input[..span.start].matches('\n').count() + 1, self),
+            _ => format!("{}: {}", name, self)
+        }
+    }
 }
 
 impl std::fmt::Display for InterpreterError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.message.as_str())
+        match self {
+            InterpreterError::Syntax{message, ..} => f.write_str(message),
+            InterpreterError::CommandNotFound{command, ..} => write!(f, "{}: command not found", command),
+            InterpreterError::PermissionDenied{command, ..} => write!(f, "{}: Permission denied", command),
+            InterpreterError::BadSubstitution{message, ..} => f.write_str(message),
+            InterpreterError::Redirection{target, error, ..} => write!(f, "{}: {}", target, strerror(error)),
+            InterpreterError::UnboundVariable{name, ..} => write!(f, "{}: unbound variable", name),
+            InterpreterError::Io{error, ..} => f.write_str(&strerror(error)),
+            InterpreterError::Runtime{message, ..} => f.write_str(message)
+        }
     }
 }
 
 impl std::fmt::Debug for InterpreterError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.message.as_str())
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for InterpreterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InterpreterError::Redirection{error, ..} | InterpreterError::Io{error, ..} => Some(error),
+            _ => None
+        }
     }
 }
 
-impl <T: Error> From<T> for InterpreterError {
-    fn from(error: T) -> Self {
-        InterpreterError{message:format!("{}", error)}
+impl From<io::Error> for InterpreterError {
+    fn from(error: io::Error) -> Self {
+        InterpreterError::Io{error, span: None}
     }
 }
 
-pub type InterpreterResult<T> = std::result::Result<T, InterpreterError>;
\ No newline at end of file
+pub type InterpreterResult<T> = std::result::Result<T, InterpreterError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostic() {
+        let err = InterpreterError::bad_substitution("${a b}: bad substitution").or_span(Span::new(13, 19));
+        assert_eq!(err.diagnostic("ls\necho x && ${a b} y\n"), "${a b}: bad substitution\n  echo x && ${a b} y\n            ^^^^^^");
+        assert_eq!(err.located("rc", "ls\necho x && ${a b} y\n"), "rc: line 2: ${a b}: bad substitution");
+        assert_eq!(err.or_span(Span::new(0, 1)).span(), Some(Span::new(13, 19)));
+        assert_eq!(InterpreterError::runtime("oops").diagnostic("x"), "oops");
+        assert_eq!(InterpreterError::runtime("oops").located("rc", "x"), "rc: oops");
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(InterpreterError::syntax("unexpected EOF").exit_code(), 2);
+        assert_eq!(InterpreterError::CommandNotFound{command: "x".to_string(), span: None}.exit_code(), 127);
+        assert_eq!(InterpreterError::from(io::Error::from(io::ErrorKind::Other)).exit_code(), 1);
+    }
+}
@@ -0,0 +1,443 @@
+use crate::physical::{ExitStatus, Process};
+use crate::signals;
+use crate::spawner::ProcessGroup;
+use libc::{c_int, pid_t};
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the SIGCHLD handler whenever a child exits, stops or continues.
+static CHILD_CHANGED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn child_changed(_: c_int) {
+    CHILD_CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGCHLD handler so that jobs which change state in the background are
+/// noticed before the next prompt.
+pub fn watch() -> io::Result<()> {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = child_changed as extern "C" fn(c_int) as libc::sighandler_t;
+    // Reads from the terminal are restarted rather than failing when a job changes state.
+    action.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    match unsafe { libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error())
+    }
+}
+
+/// Moves the calling process into `group`, hands it the terminal and restores the default
+/// dispositions of the signals the shell ignores. Only async-signal-safe calls are made, so
+/// that it can run between fork and exec.
+pub fn enter(group: &ProcessGroup) -> io::Result<()> {
+    unsafe {
+        libc::setpgid(0, group.pgid);
+        if let Some(terminal) = group.terminal {
+            libc::tcsetpgrp(terminal, libc::getpgrp());
+        }
+    }
+    signals::restore();
+    Ok(())
+}
+
+/// Moves the child `pid` into `group` from the shell's side, so that the group exists
+/// whichever of the two gets there first. The first child to join a new group leads it.
+pub fn join(pid: pid_t, group: &mut ProcessGroup) {
+    unsafe { libc::setpgid(pid, group.pgid) };
+    if group.pgid == 0 {
+        group.pgid = pid;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Every process that has not exited is stopped, the last of them by the given signal.
+    Stopped(c_int),
+    /// Every process has exited. The last reported the given status.
+    Done(ExitStatus)
+}
+
+struct Member {
+    pid: Option<pid_t>,
+    status: Option<ExitStatus>,
+    stopped: Option<c_int>
+}
+
+/// The processes of one pipeline, which share a process group.
+pub struct Job {
+    pgid: pid_t,
+    command: String,
+    members: Vec<Member>,
+    /// The terminal modes the job had when it last left the foreground.
+    modes: Option<libc::termios>
+}
+
+impl Job {
+    /// Groups the processes started for `command`. Those that are not child processes, such
+    /// as builtins, have already finished and are waited for straight away. The others are
+    /// reaped by the job itself.
+    pub fn new(pgid: pid_t, command: String, processes: Vec<Box<dyn Process>>) -> Job {
+        let members = processes.into_iter().map(|mut process| {
+            let pid = process.pid();
+            let status = if pid.is_none() { Some(process.wait()) } else { None };
+            Member{ pid, status, stopped: None }
+        }).collect();
+        Job{ pgid, command, members, modes: None }
+    }
+
+    pub fn pgid(&self) -> pid_t {
+        self.pgid
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn state(&self) -> State {
+        let mut stopped = None;
+        for member in &self.members {
+            match (member.status, member.stopped) {
+                (Some(_), _) => {}
+                (None, Some(signal)) => stopped = Some(signal),
+                (None, None) => return State::Running
+            }
+        }
+        match stopped {
+            Some(signal) => State::Stopped(signal),
+            None => State::Done(self.members.last().and_then(|member| member.status).unwrap_or_default())
+        }
+    }
+
+    /// The status of each process. As with `$?`, a stopped process reports 128 plus the
+    /// signal that stopped it.
+    pub fn statuses(&self) -> Vec<ExitStatus> {
+        self.members.iter()
+            .map(|member| member.status.unwrap_or_else(|| ExitStatus::new(128 + member.stopped.unwrap_or_default())))
+            .collect()
+    }
+
+    /// Collects a change in the state of one of the job's processes, waiting for one unless
+    /// `flags` includes `WNOHANG`. Returns whether anything changed.
+    fn update(&mut self, flags: c_int) -> bool {
+        let mut status = 0;
+        let pid = loop {
+            let pid = unsafe { libc::waitpid(-self.pgid, &mut status, flags) };
+            if pid >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break pid;
+            }
+        };
+        if pid < 0 {
+            // Nothing is left to wait for, so processes reaped elsewhere are taken to have
+            // failed.
+            let mut changed = false;
+            for member in self.members.iter_mut().filter(|member| member.status.is_none()) {
+                member.status = Some(ExitStatus::FAILURE);
+                changed = true;
+            }
+            return changed;
+        }
+        if let Some(member) = self.members.iter_mut().find(|member| member.pid == Some(pid)) {
+            if libc::WIFSTOPPED(status) {
+                member.stopped = Some(libc::WSTOPSIG(status));
+            } else if libc::WIFCONTINUED(status) {
+                member.stopped = None;
+            } else {
+                use std::os::unix::process::ExitStatusExt;
+                member.status = Some(std::process::ExitStatus::from_raw(status).into());
+                member.stopped = None;
+            }
+        }
+        pid > 0
+    }
+
+    /// Continues every stopped process in the job.
+    pub fn resume(&mut self) -> io::Result<()> {
+        if unsafe { libc::kill(-self.pgid, libc::SIGCONT) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for member in &mut self.members {
+            member.stopped = None;
+        }
+        Ok(())
+    }
+}
+
+/// The terminal of an interactive shell, which is handed to each job in the foreground.
+struct Terminal {
+    fd: RawFd,
+    /// The shell's own process group.
+    pgid: pid_t,
+    modes: Option<libc::termios>,
+    _ignored: signals::Ignored
+}
+
+impl Terminal {
+    fn modes(&self) -> Option<libc::termios> {
+        let mut modes: libc::termios = unsafe { std::mem::zeroed() };
+        match unsafe { libc::tcgetattr(self.fd, &mut modes) } {
+            0 => Some(modes),
+            _ => None
+        }
+    }
+
+    fn give(&self, job: &Job) {
+        unsafe { libc::tcsetpgrp(self.fd, job.pgid) };
+        if let Some(modes) = &job.modes {
+            unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, modes) };
+        }
+    }
+
+    /// Takes the terminal back for the shell, returning the modes the job left it in.
+    fn reclaim(&self) -> Option<libc::termios> {
+        unsafe { libc::tcsetpgrp(self.fd, self.pgid) };
+        let modes = self.modes();
+        if let Some(shell) = &self.modes {
+            unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, shell) };
+        }
+        modes
+    }
+}
+
+struct Entry {
+    id: usize,
+    job: Job,
+    /// The state the job was in when it was last described to the user.
+    reported: State
+}
+
+/// The jobs that have been stopped or put in the background, numbered from 1.
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Vec<Entry>,
+    /// Job numbers from the least to the most recently stopped or resumed. The last is the
+    /// current job, `%+`, and the one before it the previous job, `%-`.
+    recent: Vec<usize>,
+    terminal: Option<Terminal>,
+    /// Set in a forked copy of the shell, which may list its parent's jobs but cannot wait
+    /// for them.
+    detached: bool
+}
+
+impl JobTable {
+    /// Makes the shell the foreground process group of the terminal open on `fd`, once the
+    /// shell has been brought to the foreground, and ignores the signals the terminal sends
+    /// to stop it. Does nothing if `fd` is not a terminal.
+    pub fn take_terminal(&mut self, fd: RawFd) -> io::Result<()> {
+        if self.terminal.is_some() || unsafe { libc::isatty(fd) } != 1 {
+            return Ok(());
+        }
+        unsafe {
+            while libc::tcgetpgrp(fd) != libc::getpgrp() {
+                libc::kill(-libc::getpgrp(), libc::SIGTTIN);
+            }
+        }
+        let ignored = signals::ignore(&signals::INTERACTIVE);
+        // A session leader already leads its own group and cannot be moved.
+        unsafe { libc::setpgid(0, 0) };
+        // Children are handed the terminal through a descriptor that redirections of their
+        // standard input cannot replace.
+        let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pgid = unsafe { libc::getpgrp() };
+        if unsafe { libc::tcsetpgrp(fd, pgid) } != 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        let mut terminal = Terminal{ fd, pgid, modes: None, _ignored: ignored };
+        terminal.modes = terminal.modes();
+        self.terminal = Some(terminal);
+        Ok(())
+    }
+
+    /// The descriptor of the terminal that foreground jobs are given.
+    pub fn terminal(&self) -> Option<RawFd> {
+        self.terminal.as_ref().map(|terminal| terminal.fd)
+    }
+
+    /// Gives up waiting for the jobs, as a forked copy of the shell must.
+    pub fn detach(&mut self) {
+        self.detached = true;
+        // The copy has already restored its signals, and cannot safely take the lock that
+        // dropping the terminal's guard would.
+        std::mem::forget(self.terminal.take());
+    }
+
+    /// Runs `job` in the foreground, resuming it if it is stopped, until it finishes or
+    /// stops again. A job that stops is kept in the table as number `id`, or the next free
+    /// number, and described in the returned line.
+    pub fn foreground(&mut self, mut job: Job, id: Option<usize>) -> (Vec<ExitStatus>, Option<String>) {
+        if let Some(terminal) = &self.terminal {
+            terminal.give(&job);
+        }
+        if let State::Stopped(_) = job.state() {
+            let _ = job.resume();
+        }
+        while job.state() == State::Running && !self.detached && job.update(libc::WUNTRACED) {}
+        if let Some(terminal) = &self.terminal {
+            job.modes = terminal.reclaim();
+        }
+        let statuses = job.statuses();
+        match job.state() {
+            State::Stopped(_) => {
+                let id = self.insert(job, id);
+                let line = self.jobs.iter().find(|entry| entry.id == id).map(|entry| self.describe(entry, false));
+                // The line starts afresh after whatever the terminal echoed for Ctrl-Z.
+                match &self.terminal {
+                    Some(_) => (statuses, line.map(|line| format!("\n{}", line))),
+                    None => (statuses, line)
+                }
+            }
+            _ => (statuses, None)
+        }
+    }
+
+    /// Continues the stopped job `id` in the background, returning the line to announce it
+    /// with.
+    pub fn background(&mut self, id: usize) -> Result<String, String> {
+        let entry = self.jobs.iter_mut().find(|entry| entry.id == id).ok_or_else(|| format!("%{}: no such job", id))?;
+        if !matches!(entry.job.state(), State::Stopped(_)) {
+            return Err(format!("job {} already in background", id));
+        }
+        entry.job.resume().map_err(|err| err.to_string())?;
+        entry.reported = State::Running;
+        let command = entry.job.command.clone();
+        self.recent.retain(|&recent| recent != id);
+        self.recent.push(id);
+        Ok(format!("[{}]{} {} &", id, self.mark(id), command))
+    }
+
+    /// Finds the job named by `spec`: `%n` for job n, `%+` or `%%` for the current job and
+    /// `%-` for the previous one. Without a spec the current job is meant.
+    pub fn resolve(&self, spec: Option<&str>) -> Result<usize, String> {
+        let id = match spec {
+            None | Some("%") | Some("%%") | Some("%+") => self.recent.last().copied(),
+            Some("%-") => self.recent.iter().rev().nth(1).copied(),
+            Some(spec) => spec.strip_prefix('%').unwrap_or(spec).parse().ok().filter(|&id| self.jobs.iter().any(|entry| entry.id == id))
+        };
+        id.ok_or_else(|| format!("{}: no such job", spec.unwrap_or("current")))
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|entry| entry.id == id).map(|entry| &entry.job)
+    }
+
+    /// Removes job `id` from the table, as when it is brought to the foreground.
+    pub fn take(&mut self, id: usize) -> Option<Job> {
+        let idx = self.jobs.iter().position(|entry| entry.id == id)?;
+        self.recent.retain(|&recent| recent != id);
+        Some(self.jobs.remove(idx).job)
+    }
+
+    /// Collects whatever has happened to the jobs without waiting.
+    pub fn poll(&mut self) {
+        if self.detached {
+            return;
+        }
+        for entry in &mut self.jobs {
+            while !matches!(entry.job.state(), State::Done(_)) && entry.job.update(libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED) {}
+        }
+    }
+
+    /// Describes every job, or with `changed` only those whose state has changed since they
+    /// were last described. Finished jobs are forgotten once they have been described.
+    pub fn report(&mut self, changed: bool, long: bool) -> Vec<String> {
+        self.poll();
+        let lines = self.jobs.iter()
+            .filter(|entry| !changed || entry.reported != entry.job.state())
+            .map(|entry| self.describe(entry, long))
+            .collect();
+        for entry in &mut self.jobs {
+            entry.reported = entry.job.state();
+        }
+        let done: Vec<usize> = self.jobs.iter().filter(|entry| matches!(entry.reported, State::Done(_))).map(|entry| entry.id).collect();
+        self.jobs.retain(|entry| !done.contains(&entry.id));
+        self.recent.retain(|id| !done.contains(id));
+        lines
+    }
+
+    /// Describes the jobs that have changed state since the last SIGCHLD was noticed.
+    pub fn changes(&mut self) -> Vec<String> {
+        match CHILD_CHANGED.swap(false, Ordering::SeqCst) {
+            true => self.report(true, false),
+            false => vec![]
+        }
+    }
+
+    /// The process group of every job.
+    pub fn pgids(&mut self) -> Vec<pid_t> {
+        self.poll();
+        self.jobs.iter().map(|entry| entry.job.pgid).collect()
+    }
+
+    fn insert(&mut self, job: Job, id: Option<usize>) -> usize {
+        let id = id.unwrap_or_else(|| self.jobs.iter().map(|entry| entry.id).max().unwrap_or_default() + 1);
+        let idx = self.jobs.partition_point(|entry| entry.id < id);
+        let reported = job.state();
+        self.jobs.insert(idx, Entry{ id, job, reported });
+        self.recent.retain(|&recent| recent != id);
+        self.recent.push(id);
+        id
+    }
+
+    fn mark(&self, id: usize) -> char {
+        match self.recent.iter().rev().position(|&recent| recent == id) {
+            Some(0) => '+',
+            Some(1) => '-',
+            _ => ' '
+        }
+    }
+
+    /// Formats a job the way `jobs` lists it, with its process group if `long`.
+    fn describe(&self, entry: &Entry, long: bool) -> String {
+        let state = match entry.job.state() {
+            State::Running => "Running".to_string(),
+            State::Stopped(_) => "Stopped".to_string(),
+            State::Done(status) if status.success() => "Done".to_string(),
+            State::Done(status) => format!("Exit {}", status.code())
+        };
+        match long {
+            true => format!("[{}]{} {:>5} {:<24}{}", entry.id, self.mark(entry.id), entry.job.pgid, state, entry.job.command),
+            false => format!("[{}]{}  {:<24}{}", entry.id, self.mark(entry.id), state, entry.job.command)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physical::CompletedProcess;
+
+    fn job(command: &str) -> Job {
+        Job::new(0, command.to_string(), vec![Box::new(CompletedProcess{ result: ExitStatus::new(3) })])
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut table = JobTable::default();
+        assert_eq!(table.resolve(None), Err("current: no such job".to_string()));
+        table.insert(job("a"), None);
+        table.insert(job("b"), None);
+        table.insert(job("c"), Some(7));
+        assert_eq!(table.resolve(Some("%+")), Ok(7));
+        assert_eq!(table.resolve(Some("%-")), Ok(2));
+        assert_eq!(table.resolve(Some("%1")), Ok(1));
+        assert_eq!(table.resolve(Some("%3")), Err("%3: no such job".to_string()));
+        assert_eq!(table.take(7).map(|job| job.command), Some("c".to_string()));
+        assert_eq!(table.resolve(None), Ok(2));
+        assert_eq!(table.insert(job("d"), None), 3);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut table = JobTable::default();
+        table.insert(job("false"), None);
+        assert_eq!(table.report(false, false), vec!["[1]+  Exit 3                  false"]);
+        assert!(table.report(false, false).is_empty());
+        assert_eq!(table.resolve(None), Err("current: no such job".to_string()));
+    }
+}
@@ -0,0 +1,408 @@
+use crate::errors::{InterpreterError, InterpreterResult, Span};
+
+const ESCAPE: char = '\\';
+const SINGLE_QUOTE: char = '\'';
+const DOUBLE_QUOTE: char = '"';
+const BACKTICK: char = '`';
+const SUBSTITUTION: char = '$';
+const COMMENT: char = '#';
+
+/// How deeply quotes and expansions may nest within a word.
+const MAX_NESTING: usize = 256;
+
+/// Operators that separate words. Longer operators must come before their prefixes.
+const OPERATORS: &[&str] = &["&&", "||", ">>", ">|", "<&", "|", ";", ">", "<"];
+
+/// Operators that separate one command from the next.
+const CONTROL_OPERATORS: &[&str] = &["&&", "||", "|", ";"];
+
+/// Reserved words that may precede a pipeline.
+const PIPELINE_PREFIXES: &[&str] = &["!", "time"];
+
+/// Operators after which a newline does not terminate the command.
+const CONTINUATIONS: &[&str] = &["&&", "||", "|"];
+
+/// A raw token and the span of input it was read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub text: String,
+    pub span: Span
+}
+
+/// Splits shell input into raw tokens. Words keep their quotes and escapes intact so that
+/// `substitution` can later decide what is expanded and what is literal, while operators
+/// (and newlines, which are reported as `;`) are emitted as their own tokens.
+pub fn tokenize<S: AsRef<str>>(input: S) -> InterpreterResult<Vec<Token>> {
+    let input = input.as_ref();
+    let mut offsets: Vec<usize> = input.char_indices().map(|(offset, _)| offset).collect();
+    offsets.push(input.len());
+    let mut scanner = Scanner { chars: input.chars().collect(), offsets, pos: 0, depth: 0 };
+    let mut tokens: Vec<Token> = vec![];
+    // Inside `[[ ... ]]` parentheses group expressions and the operand of `=~` is a regex.
+    let mut conditional = false;
+    while let Some(c) = scanner.peek(0) {
+        let start = scanner.pos;
+        let text = match c {
+            '\n' => {
+                scanner.pos += 1;
+                match tokens.last() {
+                    Some(last) if last.text != ";" && !CONTINUATIONS.contains(&last.text.as_str()) => ";".to_string(),
+                    _ => continue
+                }
+            }
+            c if c.is_whitespace() => {
+                scanner.pos += 1;
+                continue;
+            }
+            COMMENT => {
+                scanner.skip_comment();
+                continue;
+            }
+            '(' if scanner.peek(1) == Some('(') && command_position(&tokens) => {
+                let mut token = String::new();
+                scanner.balanced(&mut token, '(', ')').map_err(|err| err.or_span(scanner.span(start)))?;
+                token
+            }
+            _ if conditional && tokens.last().is_some_and(|last| last.text == "=~") => {
+                scanner.regex().map_err(|err| err.or_span(scanner.span(start)))?
+            }
+            '(' | ')' if conditional => {
+                scanner.pos += 1;
+                c.to_string()
+            }
+            _ => match scanner.operator() {
+                Some(operator) => operator.to_string(),
+                None => {
+                    let word = scanner.word(conditional).map_err(|err| err.or_span(scanner.span(start)))?;
+                    if word == "[[" && command_position(&tokens) {
+                        conditional = true;
+                    } else if word == "]]" && conditional {
+                        conditional = false;
+                    }
+                    word
+                }
+            }
+        };
+        tokens.push(Token { text, span: scanner.span(start) });
+    }
+    Ok(tokens)
+}
+
+/// Splits tokens into the complete commands on each line of the `input` they were read
+/// from, so that each line can run before the next is compiled. A line ends at a newline
+/// outside `[[ ... ]]` that does not follow an operator continuing the command.
+pub fn lines(tokens: Vec<Token>, input: &str) -> Vec<Vec<Token>> {
+    let (mut lines, mut line) = (vec![], vec![]);
+    let mut conditional = false;
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match token.text.as_str() {
+            "[[" if command_position(&line) => conditional = true,
+            "]]" => conditional = false,
+            _ => {}
+        }
+        let newline = |next: &Token| input.get(token.span.start..next.span.start).is_some_and(|gap| gap.contains('\n'));
+        let end = token.text == ";" && !conditional && tokens.peek().is_some_and(newline);
+        line.push(token);
+        if end {
+            lines.push(std::mem::take(&mut line));
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+pub fn is_operator<S: AsRef<str>>(token: S) -> bool {
+    OPERATORS.contains(&token.as_ref())
+}
+
+pub fn is_control_operator<S: AsRef<str>>(token: S) -> bool {
+    CONTROL_OPERATORS.contains(&token.as_ref())
+}
+
+/// Whether `token` is a reserved word that may precede a pipeline, after which a command
+/// word follows.
+pub fn is_pipeline_prefix<S: AsRef<str>>(token: S) -> bool {
+    PIPELINE_PREFIXES.contains(&token.as_ref())
+}
+
+/// Whether the next word begins a command, following an operator or a pipeline prefix.
+fn command_position(tokens: &[Token]) -> bool {
+    match tokens.last() {
+        None => true,
+        Some(last) => is_operator(&last.text) || is_pipeline_prefix(&last.text)
+    }
+}
+
+struct Scanner {
+    chars: Vec<char>,
+    /// The byte offset of each character, followed by the length of the input.
+    offsets: Vec<usize>,
+    pos: usize,
+    /// How many `atom`s are being consumed, one inside the other.
+    depth: usize
+}
+
+impl Scanner {
+    /// Returns the span from the character at `start` up to the current position.
+    fn span(&self, start: usize) -> Span {
+        let offset = |pos: usize| self.offsets[pos.min(self.offsets.len() - 1)];
+        Span::new(offset(start), offset(self.pos))
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek(0);
+        self.pos += 1;
+        c
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.peek(i) == Some(c))
+    }
+
+    fn operator(&mut self) -> Option<&'static str> {
+        let operator = OPERATORS.iter().find(|op| self.starts_with(op))?;
+        self.pos += operator.len();
+        Some(operator)
+    }
+
+    fn skip_comment(&mut self) {
+        while let Some(c) = self.peek(0) {
+            if c == '\n' {
+                break;
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn at_word_boundary(&self) -> bool {
+        match self.peek(0) {
+            None => true,
+            Some(c) if c.is_whitespace() => true,
+            Some(_) => OPERATORS.iter().any(|op| self.starts_with(op))
+        }
+    }
+
+    fn word(&mut self, conditional: bool) -> InterpreterResult<String> {
+        let mut word = String::new();
+        while !(self.at_word_boundary() || conditional && self.peek(0) == Some(')')) {
+            self.atom(&mut word)?;
+        }
+        Ok(word)
+    }
+
+    /// Consumes the right hand side of `=~`, where parentheses and `|` belong to the regex.
+    fn regex(&mut self) -> InterpreterResult<String> {
+        let mut word = String::new();
+        let mut depth = 0;
+        loop {
+            match self.peek(0) {
+                None => break,
+                Some(c) if c.is_whitespace() && depth == 0 => break,
+                Some(')') if depth == 0 => break,
+                Some(_) if depth == 0 && (self.starts_with("&&") || self.starts_with("||")) => break,
+                Some(c) => {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    self.atom(&mut word)?;
+                }
+            }
+        }
+        Ok(word)
+    }
+
+    /// Consumes a single unit of a word: a character, an escape sequence, a quoted string or
+    /// a `$`/backtick expansion, appending it verbatim to `word`.
+    fn atom(&mut self, word: &mut String) -> InterpreterResult<()> {
+        if self.depth >= MAX_NESTING {
+            return Err(InterpreterError::syntax("quotes and expansions nested too deeply"));
+        }
+        self.depth += 1;
+        let result = self.nested_atom(word);
+        self.depth -= 1;
+        result
+    }
+
+    fn nested_atom(&mut self, word: &mut String) -> InterpreterResult<()> {
+        match self.next() {
+            Some(ESCAPE) => {
+                word.push(ESCAPE);
+                if let Some(c) = self.next() {
+                    word.push(c);
+                }
+            }
+            Some(SINGLE_QUOTE) => {
+                word.push(SINGLE_QUOTE);
+                self.until(word, SINGLE_QUOTE)?;
+            }
+            Some(DOUBLE_QUOTE) => {
+                word.push(DOUBLE_QUOTE);
+                self.double_quoted(word)?;
+            }
+            Some(BACKTICK) => {
+                word.push(BACKTICK);
+                self.until(word, BACKTICK)?;
+            }
+            Some(SUBSTITUTION) => {
+                word.push(SUBSTITUTION);
+                self.expansion(word)?;
+            }
+            Some(c) => word.push(c),
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn until(&mut self, word: &mut String, close: char) -> InterpreterResult<()> {
+        loop {
+            match self.next() {
+                None => return Err(InterpreterError::syntax(format!("unexpected EOF while looking for matching `{}'", close))),
+                Some(ESCAPE) if close != SINGLE_QUOTE => {
+                    word.push(ESCAPE);
+                    if let Some(c) = self.next() {
+                        word.push(c);
+                    }
+                }
+                Some(c) => {
+                    word.push(c);
+                    if c == close {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn double_quoted(&mut self, word: &mut String) -> InterpreterResult<()> {
+        loop {
+            match self.peek(0) {
+                None => return Err(InterpreterError::syntax("unexpected EOF while looking for matching `\"'")),
+                Some(DOUBLE_QUOTE) => {
+                    self.pos += 1;
+                    word.push(DOUBLE_QUOTE);
+                    return Ok(());
+                }
+                Some(SINGLE_QUOTE) => {
+                    self.pos += 1;
+                    word.push(SINGLE_QUOTE);
+                }
+                Some(_) => self.atom(word)?
+            }
+        }
+    }
+
+    fn expansion(&mut self, word: &mut String) -> InterpreterResult<()> {
+        match self.peek(0) {
+            Some('(') => self.balanced(word, '(', ')'),
+            Some('{') => self.balanced(word, '{', '}'),
+            _ => Ok(())
+        }
+    }
+
+    /// Consumes a bracketed region, including any nested brackets and quoted strings.
+    fn balanced(&mut self, word: &mut String, open: char, close: char) -> InterpreterResult<()> {
+        let mut depth = 0;
+        loop {
+            match self.peek(0) {
+                None => return Err(InterpreterError::syntax(format!("unexpected EOF while looking for matching `{}'", close))),
+                Some(c) if c == open => {
+                    self.pos += 1;
+                    word.push(c);
+                    depth += 1;
+                }
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    word.push(c);
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                Some(_) => self.atom(word)?
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(input: &str) -> InterpreterResult<Vec<String>> {
+        Ok(tokenize(input)?.into_iter().map(|token| token.text).collect())
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(texts("ls -l  src").unwrap(), vec!["ls", "-l", "src"]);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(texts("a&&b||c|d>e>>f>|g;h").unwrap(), vec!["a", "&&", "b", "||", "c", "|", "d", ">", "e", ">>", "f", ">|", "g", ";", "h"]);
+        assert_eq!(texts("cat</f 0<&3").unwrap(), vec!["cat", "<", "/f", "0", "<&", "3"]);
+    }
+
+    #[test]
+    fn test_quotes_preserved() {
+        assert_eq!(texts("echo 'a b' \"c && d\" e\\ f").unwrap(), vec!["echo", "'a b'", "\"c && d\"", "e\\ f"]);
+    }
+
+    #[test]
+    fn test_arithmetic_expansion() {
+        assert_eq!(texts("echo $(( 1 + (2 * 3) ))x").unwrap(), vec!["echo", "$(( 1 + (2 * 3) ))x"]);
+    }
+
+    #[test]
+    fn test_arithmetic_command() {
+        assert_eq!(texts("(( i++ )) && echo").unwrap(), vec!["(( i++ ))", "&&", "echo"]);
+        assert_eq!(texts("! (( i ))").unwrap(), vec!["!", "(( i ))"]);
+    }
+
+    #[test]
+    fn test_newlines() {
+        assert_eq!(texts("\na # comment\nb &&\nc\n").unwrap(), vec!["a", ";", "b", "&&", "c", ";"]);
+    }
+
+    #[test]
+    fn test_lines() {
+        let input = "a; b\nc &&\nd;\n[[ e\n]]\n\nf";
+        let texts: Vec<Vec<String>> = lines(tokenize(input).unwrap(), input).into_iter()
+            .map(|line| line.into_iter().map(|token| token.text).collect())
+            .collect();
+        assert_eq!(texts, vec![vec!["a", ";", "b", ";"], vec!["c", "&&", "d", ";"], vec!["[[", "e", ";", "]]", ";"], vec!["f"]]);
+        assert!(lines(vec![], "").is_empty());
+    }
+
+    #[test]
+    fn test_conditional() {
+        assert_eq!(texts("[[ (a == b) && $x =~ ^(x|y)+$ ]] || [[ y ]]").unwrap(),
+                   vec!["[[", "(", "a", "==", "b", ")", "&&", "$x", "=~", "^(x|y)+$", "]]", "||", "[[", "y", "]]"]);
+        assert_eq!(texts("echo (a)").unwrap(), vec!["echo", "(a)"]);
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize("é 'a b'>x\ny").unwrap();
+        let spans: Vec<Span> = tokens.iter().map(|token| token.span).collect();
+        assert_eq!(spans, vec![Span::new(0, 2), Span::new(3, 8), Span::new(8, 9), Span::new(9, 10), Span::new(10, 11), Span::new(11, 12)]);
+        assert_eq!(tokenize("ls \"abc").unwrap_err().span(), Some(Span::new(3, 7)));
+    }
+
+    #[test]
+    fn test_unterminated() {
+        assert!(texts("echo 'abc").is_err());
+        assert!(texts("echo \"abc").is_err());
+        assert!(texts("echo $((1 + 2)").is_err());
+        assert!(texts(&"\"$(".repeat(100_000)).is_err());
+    }
+}
@@ -0,0 +1,3192 @@
+//! rsh, a small shell that can be embedded through `Interpreter` or run as a binary.
+
+use bumpalo::Bump;
+use std::iter::Peekable;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+
+mod substitution;
+mod errors;
+mod compiler;
+mod physical;
+mod lexer;
+mod arithmetic;
+mod conditional;
+mod pattern;
+mod builtin;
+mod spawner;
+mod options;
+mod jobs;
+mod signals;
+mod traps;
+mod aliases;
+use physical::*;
+pub use errors::{InterpreterError, InterpreterResult, Span};
+pub use physical::ExitStatus;
+pub use builtin::{Builtin, BuiltinStdio};
+pub use physical::Process;
+pub use spawner::{MockSpawner, ProcessGroup, ProcessSpawner, Scripted, SpawnRecord, SpawnRequest, Spawner, Stream, StreamKind};
+use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use substitution::{substitution, fields};
+use lexer::Token;
+use options::Options;
+use jobs::{Job, JobTable};
+use traps::{Condition, Traps};
+use aliases::Aliases;
+
+pub struct Interpreter {
+    environment: Environment,
+    /// The working directory used for commands, redirections and file tests. It is kept per
+    /// interpreter rather than changing the working directory of the whole process.
+    cwd: PathBuf,
+    /// The directories saved by `pushd`, most recent first. The current directory is not
+    /// included, although it is the first element of `DIRSTACK`.
+    dirstack: Vec<String>,
+    builtins: HashMap<String, Box<dyn Builtin>>,
+    spawner: Box<dyn Spawner>,
+    streams: Streams,
+    options: Options,
+    jobs: JobTable,
+    /// The process group that commands join while a pipeline is started under job control.
+    process_group: Option<ProcessGroup>,
+    /// The read end of the pipe written by the pipeline stage being started, while it is
+    /// not the last stage.
+    piping: Option<RawFd>,
+    /// Set when a foreground command is killed by SIGINT, which abandons the rest of the
+    /// input being run.
+    interrupted: bool,
+    /// The status given to `exit`, which also abandons the rest of the input.
+    exit: Option<ExitStatus>,
+    /// The status of the last pipeline to finish.
+    last_status: ExitStatus,
+    traps: Traps,
+    /// Set while a trap runs, so that traps do not fire from inside one another.
+    trapping: bool,
+    /// How many `&&` or `||` operands and `!` pipelines are being run, whose failure is
+    /// tested rather than raising `ERR`.
+    testing: usize,
+    /// The inputs being evaluated, innermost last, with the files they were read from.
+    scripts: Vec<Script>,
+    /// The status given to `return`, which abandons the rest of the file being sourced.
+    returned: Option<ExitStatus>,
+    aliases: Aliases,
+    /// The descriptors above standard error opened with `exec`, or by a redirection for as
+    /// long as its command runs, by the number commands are given them as.
+    fds: BTreeMap<RawFd, File>
+}
+
+/// An input whose commands are being run, kept so that errors can point at where they arose:
+/// by line if it was read from the file called `name`, or otherwise with a caret.
+struct Script {
+    name: Option<String>,
+    input: String
+}
+
+/// The standard streams that commands run by an interpreter inherit. Each is the process's
+/// own unless replaced, for instance while output is being captured.
+#[derive(Default)]
+struct Streams {
+    stdin: Option<File>,
+    stdout: Option<File>,
+    stderr: Option<File>
+}
+
+impl Streams {
+    fn stdin(&self) -> Box<dyn Read + '_> {
+        match &self.stdin {
+            Some(file) => Box::new(file),
+            None => Box::new(io::stdin())
+        }
+    }
+
+    fn stdout(&self) -> Box<dyn Write + '_> {
+        match &self.stdout {
+            Some(file) => Box::new(file),
+            None => Box::new(io::stdout())
+        }
+    }
+
+    fn stderr(&self) -> Box<dyn Write + '_> {
+        match &self.stderr {
+            Some(file) => Box::new(file),
+            None => Box::new(io::stderr())
+        }
+    }
+
+    /// Duplicates a replaced stream for a child process, which otherwise inherits the
+    /// process's own.
+    fn stream(stream: &Option<File>) -> io::Result<Stream> {
+        match stream {
+            Some(file) => Ok(Stream::Fd(file.try_clone()?.into())),
+            None => Ok(Stream::Inherit)
+        }
+    }
+}
+
+/// The result of `Interpreter::capture`.
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: ExitStatus
+}
+
+type Lexer = Peekable<std::vec::IntoIter<Token>>;
+
+impl Interpreter {
+
+    /// Creates an interpreter that inherits the process's environment and working directory.
+    pub fn new() -> Self {
+        let cwd = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+        Self::with(std::env::vars().collect(), cwd)
+    }
+
+    /// Returns a builder for an interpreter with its own environment, working directory and
+    /// positional parameters.
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder::default()
+    }
+
+    fn with(mut environment: Environment, cwd: PathBuf) -> Self {
+        // An inherited PWD is only trusted if it still names the current directory.
+        let valid = environment.get("PWD").is_some_and(|pwd| Path::new(pwd).is_absolute() && physical::same_file(pwd, &cwd));
+        if !valid {
+            environment.insert("PWD".to_string(), cwd.to_string_lossy().to_string());
+        }
+        let mut interpreter = Interpreter { environment, cwd, dirstack: vec![], builtins: HashMap::new(), spawner: Box::new(ProcessSpawner), streams: Streams::default(), options: Options::default(),
+            jobs: JobTable::default(), process_group: None, piping: None, interrupted: false, exit: None, last_status: ExitStatus::SUCCESS,
+            traps: Traps::default(), trapping: false, testing: 0, scripts: vec![], returned: None, aliases: Aliases::default(), fds: BTreeMap::new() };
+        interpreter.environment.insert("-".to_string(), interpreter.options.flags());
+        interpreter.sync_dirstack();
+        interpreter
+    }
+
+    /// The logical working directory, even if `PWD` has been unset.
+    fn pwd(&self) -> String {
+        self.environment.get("PWD").cloned().unwrap_or_else(|| self.cwd.to_string_lossy().into_owned())
+    }
+
+    /// Returns the whole directory stack, starting with the current directory.
+    fn dirs(&self) -> Vec<String> {
+        let mut dirs = vec![self.pwd()];
+        dirs.extend(self.dirstack.iter().cloned());
+        dirs
+    }
+
+    /// Mirrors the directory stack into the `DIRSTACK` array.
+    fn sync_dirstack(&mut self) {
+        substitution::set_array("DIRSTACK", &self.dirs(), &mut self.environment);
+    }
+
+    /// Runs `input` to completion, returning the status of the last command.
+    pub fn run<I: AsRef<str>>(&mut self, input: I) -> InterpreterResult<ExitStatus> {
+        (self.interrupted, self.exit, self.returned) = (false, None, None);
+        let status = self.evaluate(input.as_ref(), None);
+        self.safe_point();
+        status
+    }
+
+    /// Runs the commands in the file at `path`, as a startup file or `source` does. Errors
+    /// are reported with the file's name and line rather than returned, and become the
+    /// file's status; only a file that cannot be read is an error. The file may stop early
+    /// with `return`, and the `RETURN` trap runs once it has finished.
+    pub fn source<P: AsRef<Path>>(&mut self, path: P) -> io::Result<ExitStatus> {
+        let name = path.as_ref().display().to_string();
+        let input = std::fs::read_to_string(physical::resolve(&self.cwd, path))?;
+        let status = self.evaluate(&input, Some(&name)).unwrap_or_else(|err| {
+            self.print_error(err.located(&name, &input));
+            ExitStatus::new(err.exit_code())
+        });
+        let status = self.returned.take().unwrap_or(status);
+        self.fire(Condition::Return);
+        self.safe_point();
+        Ok(status)
+    }
+
+    /// Evaluates `input`, which was read from the file called `name`, if any.
+    fn evaluate(&mut self, input: &str, name: Option<&str>) -> InterpreterResult<ExitStatus> {
+        self.scripts.push(Script{ name: name.map(str::to_string), input: input.to_string() });
+        let status = self.compile_and_eval(input);
+        self.scripts.pop();
+        status
+    }
+
+    /// Compiles and runs each line of `input` in turn, so that aliases defined on one line
+    /// apply to the next. A syntax error stops the input before its line runs.
+    fn compile_and_eval(&mut self, input: &str) -> InterpreterResult<ExitStatus> {
+        let mut lines = lexer::lines(lexer::tokenize(input)?, input).into_iter().peekable();
+        let mut status = ExitStatus::SUCCESS;
+        while let Some(line) = lines.next() {
+            let arena = Bump::new();
+            let mut lexer = self.aliases.expand(line)?.into_iter().peekable();
+            // Syntax errors without a more precise location were found at the end of the input.
+            let mut ast = Self::compile(&arena, &mut lexer).map_err(|err| err.or_span(Span::new(input.len(), input.len())))?;
+            let result = ast.eval(self);
+            if lines.peek().is_none() {
+                return Ok(result?.wait());
+            }
+            status = self.status(result);
+            if self.cancelled() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+
+    /// Expands parameters, arithmetic and a leading tilde in `word`, without splitting it
+    /// into fields.
+    pub fn expand<S: AsRef<str>>(&mut self, word: S) -> InterpreterResult<String> {
+        let context = self.context();
+        let word = substitution::tilde(word, false, &self.environment);
+        substitution(word, &mut self.environment, context)
+    }
+
+    /// Returns the status given to `exit`, if the last input run exited the shell. The
+    /// caller should then call `finish`.
+    pub fn exited(&self) -> Option<ExitStatus> {
+        self.exit
+    }
+
+    /// Runs the `EXIT` trap, as the shell does once its input is finished or `exit` is run.
+    /// The trap only ever runs once.
+    pub fn finish(&mut self) {
+        if let Some(action) = self.traps.take(Condition::Exit) {
+            self.run_trap(&action);
+        }
+    }
+
+    /// Whether the rest of the input being run has been abandoned, by `exit`, `return` or
+    /// SIGINT.
+    fn cancelled(&self) -> bool {
+        self.interrupted || self.exit.is_some() || self.returned.is_some()
+    }
+
+    /// Runs the command of a trap. Traps do not fire while it runs, and `$?` and `PIPESTATUS`
+    /// are left as they were.
+    fn run_trap(&mut self, action: &str) {
+        if action.is_empty() {
+            return;
+        }
+        let trapping = std::mem::replace(&mut self.trapping, true);
+        let (status, pipestatus) = (self.last_status, substitution::array("PIPESTATUS", &self.environment));
+        if let Err(err) = self.evaluate(action, None) {
+            self.print_error(format!("rsh: {}", err.diagnostic(action)));
+        }
+        substitution::set_array("PIPESTATUS", &pipestatus, &mut self.environment);
+        (self.last_status, self.trapping) = (status, trapping);
+    }
+
+    /// Runs the trap set on `condition`, if there is one.
+    fn fire(&mut self, condition: Condition) {
+        if self.trapping {
+            return;
+        }
+        if let Some(action) = self.traps.get(condition).map(str::to_string) {
+            self.run_trap(&action);
+        }
+    }
+
+    /// Runs the traps of the signals that have arrived since the last safe point. Called
+    /// between commands, where the shell's state is consistent.
+    fn safe_point(&mut self) {
+        if self.trapping {
+            return;
+        }
+        for action in self.traps.pending() {
+            self.run_trap(&action);
+        }
+    }
+
+    /// Runs `input`, returning whether the last command succeeded.
+    pub fn interpret<I: AsRef<str>>(&mut self, input: I) -> InterpreterResult<bool> {
+        self.run(input).map(|status| status.success())
+    }
+
+    /// Runs `input` with empty standard input, collecting everything it writes.
+    pub fn capture<I: AsRef<str>>(&mut self, input: I) -> InterpreterResult<Output> {
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        let status = self.stream(input, &[], &mut stdout, &mut stderr)?;
+        Ok(Output{ stdout, stderr, status })
+    }
+
+    /// Runs `input` with `stdin` as its standard input, copying its standard output and error
+    /// to `stdout` and `stderr` as they are written.
+    pub fn stream<I, O, E>(&mut self, input: I, stdin: &[u8], mut stdout: O, mut stderr: E) -> InterpreterResult<ExitStatus>
+        where I: AsRef<str>, O: Write + Send, E: Write + Send {
+        let (stdin_reader, mut stdin_writer) = io::pipe()?;
+        let (mut stdout_reader, stdout_writer) = io::pipe()?;
+        let (mut stderr_reader, stderr_writer) = io::pipe()?;
+        let streams = Streams{
+            stdin: Some(File::from(OwnedFd::from(stdin_reader))),
+            stdout: Some(File::from(OwnedFd::from(stdout_writer))),
+            stderr: Some(File::from(OwnedFd::from(stderr_writer)))
+        };
+        let previous = std::mem::replace(&mut self.streams, streams);
+        std::thread::scope(|scope| {
+            // Input the script never reads is abandoned once the pipe is closed below.
+            scope.spawn(move || stdin_writer.write_all(stdin));
+            let out = scope.spawn(move || io::copy(&mut stdout_reader, &mut stdout));
+            let err = scope.spawn(move || io::copy(&mut stderr_reader, &mut stderr));
+            let status = self.run(input);
+            // Closing our ends lets the copies finish once every child has exited.
+            self.streams = previous;
+            for copy in [out, err] {
+                copy.join().map_err(|_| InterpreterError::runtime("output copy panicked"))??;
+            }
+            status
+        })
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<&str> {
+        self.environment.get(name).map(String::as_str)
+    }
+
+    pub fn set_var<K: ToString, V: ToString>(&mut self, name: K, value: V) {
+        self.environment.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn unset_var(&mut self, name: &str) {
+        self.environment.remove(name);
+    }
+
+    /// Makes `builtin` available as the command `name`, replacing any earlier registration.
+    pub fn register_builtin<S: ToString, B: Builtin + 'static>(&mut self, name: S, builtin: B) {
+        self.builtins.insert(name.to_string(), Box::new(builtin));
+    }
+
+    fn print_line<D: std::fmt::Display>(&self, line: D) {
+        let _ = writeln!(self.streams.stdout(), "{}", line);
+    }
+
+    fn print_error<D: std::fmt::Display>(&self, err: D) {
+        let _ = writeln!(self.streams.stderr(), "{}", err);
+    }
+
+    /// Reports an error from a command, with its line if the command was read from a file,
+    /// or otherwise underlined in the input as the shell reports an error that escapes it.
+    fn report(&self, err: &InterpreterError) {
+        match self.scripts.last() {
+            Some(Script{ name: Some(name), input }) => self.print_error(err.located(name, input)),
+            Some(Script{ name: None, input }) => self.print_error(format!("rsh: {}", err.diagnostic(input))),
+            None => self.print_error(format!("rsh: {}", err))
+        }
+    }
+
+    /// Waits for a command that other commands in its list depend on. An error is reported
+    /// and becomes the command's status, so that the rest of the list still runs.
+    fn status(&mut self, result: InterpreterResult<Box<dyn Process>>) -> ExitStatus {
+        match result {
+            Ok(mut p) => p.wait(),
+            Err(err) => {
+                self.report(&err);
+                let status = ExitStatus::new(err.exit_code());
+                self.failed(status);
+                status
+            }
+        }
+    }
+
+    /// Handles a command that failed with `status`: unless its status is being tested, `ERR`
+    /// is raised and, with `errexit`, the shell exits.
+    fn failed(&mut self, status: ExitStatus) {
+        if self.testing > 0 {
+            return;
+        }
+        self.fire(Condition::Err);
+        if self.options.errexit && self.exit.is_none() {
+            self.exit = Some(status);
+        }
+    }
+
+    /// Exits a shell that is not interactive if `err` is the expansion of a parameter that is
+    /// not set under `nounset`, as POSIX requires.
+    fn unbound(&mut self, err: &InterpreterError) {
+        if let InterpreterError::UnboundVariable{..} = err {
+            if !self.interactive() && self.exit.is_none() {
+                self.exit = Some(ExitStatus::new(err.exit_code()));
+            }
+        }
+    }
+
+    /// Prints a command that is about to run after `PS4`, as `xtrace` does.
+    fn trace(&mut self, words: &[String]) {
+        let context = self.context();
+        let ps4 = self.environment.get("PS4").cloned().unwrap_or_else(|| "+ ".to_string());
+        let prefix = substitution(ps4, &mut self.environment, context).unwrap_or_default();
+        self.print_error(format!("{}{}", prefix, words.join(" ")));
+    }
+
+    /// Turns the shell option `name` on or off, returning false if there is no such option.
+    /// Turning on `monitor` takes control of the terminal if standard input is one.
+    fn set_option(&mut self, name: &str, enabled: bool) -> bool {
+        if !self.options.set(name, enabled) {
+            return false;
+        }
+        self.environment.insert("-".to_string(), self.options.flags());
+        if name == "monitor" && enabled {
+            let taken = jobs::watch().and_then(|_| match self.streams.stdin {
+                Some(_) => Ok(()),
+                None => self.jobs.take_terminal(libc::STDIN_FILENO)
+            });
+            if let Err(err) = taken {
+                self.print_error(format!("cannot set terminal process group: {}", err));
+            }
+        }
+        true
+    }
+
+    /// Opens `file` as the descriptor `fd` of the shell and the commands it runs, or closes
+    /// it. Standard input, output and error are replaced, but may not be closed.
+    fn set_fd(&mut self, fd: RawFd, file: Option<File>) -> InterpreterResult<()> {
+        // Closing the old file first frees its number for the new one.
+        self.fds.remove(&fd);
+        self.replace_fd(fd, file).map(drop)
+    }
+
+    /// Replaces the shell's descriptor `fd` with `file`, or closes it, returning the file it
+    /// had before so that `restore_fd` can put it back.
+    fn replace_fd(&mut self, fd: RawFd, file: Option<File>) -> InterpreterResult<Option<File>> {
+        let stream = match fd {
+            0 => &mut self.streams.stdin,
+            1 => &mut self.streams.stdout,
+            2 => &mut self.streams.stderr,
+            _ => {
+                let previous = self.fds.remove(&fd);
+                if let Some(file) = file {
+                    self.fds.insert(fd, place(file, fd)?);
+                }
+                return Ok(previous);
+            }
+        };
+        match file {
+            Some(file) => Ok(stream.replace(file)),
+            None => Err(InterpreterError::runtime(format!("{}: cannot close a standard stream", fd)))
+        }
+    }
+
+    /// Puts back the file `replace_fd` replaced on the descriptor `fd`.
+    fn restore_fd(&mut self, fd: RawFd, previous: Option<File>) {
+        match (fd, previous) {
+            (0, previous) => self.streams.stdin = previous,
+            (1, previous) => self.streams.stdout = previous,
+            (2, previous) => self.streams.stderr = previous,
+            (fd, Some(previous)) => { self.fds.insert(fd, previous); }
+            (fd, None) => { self.fds.remove(&fd); }
+        }
+    }
+
+    /// Duplicates the shell's descriptor `fd`, as the operand of `>&` or `<&` names it.
+    fn duplicate(&self, operand: &str) -> InterpreterResult<File> {
+        let invalid = || InterpreterError::runtime(format!("{}: Bad file descriptor", operand));
+        let fd = operand.parse::<RawFd>().map_err(|_| invalid())?;
+        let stream = match fd {
+            0 => &self.streams.stdin,
+            1 => &self.streams.stdout,
+            2 => &self.streams.stderr,
+            fd => return Ok(self.fds.get(&fd).ok_or_else(invalid)?.try_clone()?)
+        };
+        match stream {
+            Some(file) => Ok(file.try_clone()?),
+            // SAFETY: the process's own standard streams stay open for as long as it runs.
+            None => Ok(File::from(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?))
+        }
+    }
+
+    /// The state of the shell that expansions depend on.
+    fn context(&self) -> substitution::Context {
+        substitution::Context{ nounset: self.options.nounset, status: self.last_status.code() }
+    }
+
+    /// Whether the shell is interactive, which is to say it has taken control of a terminal.
+    fn interactive(&self) -> bool {
+        self.jobs.terminal().is_some()
+    }
+
+    /// Reports jobs that have finished or stopped in the background since they were last
+    /// reported, as an interactive shell does before each prompt.
+    pub fn notify(&mut self) {
+        for line in self.jobs.changes() {
+            self.print_error(line);
+        }
+    }
+
+    /// Records the statuses of a pipeline's stages in `PIPESTATUS` and returns the status of
+    /// the pipeline: that of the last stage or, with `pipefail`, of the last to fail. A stage
+    /// killed by SIGINT interrupts the shell as well, unless SIGINT is trapped.
+    fn pipeline_status(&mut self, statuses: &[ExitStatus]) -> ExitStatus {
+        if statuses.iter().any(|status| status.signal() == Some(libc::SIGINT)) {
+            match self.traps.get(Condition::Signal(libc::SIGINT)) {
+                Some(_) => signals::mark(libc::SIGINT),
+                None => self.interrupted = true
+            }
+        }
+        let codes: Vec<i32> = statuses.iter().map(ExitStatus::code).collect();
+        substitution::set_array("PIPESTATUS", &codes, &mut self.environment);
+        self.last_status = match statuses.iter().rev().find(|status| !status.success()) {
+            Some(&failure) if self.options.pipefail => failure,
+            _ => statuses.last().copied().unwrap_or_default()
+        };
+        self.last_status
+    }
+
+    /// Returns the working directory that commands run by this interpreter start in.
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+
+    fn compile<'a>(arena: &'a Bump, lexer: &mut Lexer) -> InterpreterResult<ArenaStatement<'a>> {
+        let mut statements = vec![Self::compile_and_or(arena, lexer)?];
+        while let Some(";") = lexer.peek().map(|token| token.text.as_str()) {
+            lexer.next();
+            if lexer.peek().is_none() {
+                break;
+            }
+            statements.push(Self::compile_and_or(arena, lexer)?);
+        }
+        match statements.len() {
+            1 => Ok(statements.remove(0)),
+            _ => Ok(Self::alloc(arena, Sequence{statements}))
+        }
+    }
+
+    fn compile_and_or<'a>(arena: &'a Bump, lexer: &mut Lexer) -> InterpreterResult<ArenaStatement<'a>> {
+        let first = Self::compile_pipeline(arena, lexer)?;
+        let mut rest = vec![];
+        loop {
+            let connective = match lexer.peek().map(|token| token.text.as_str()) {
+                Some("&&") => Connective::And,
+                Some("||") => Connective::Or,
+                _ => break
+            };
+            lexer.next();
+            rest.push((connective, Self::compile_pipeline(arena, lexer)?));
+        }
+        if rest.is_empty() {
+            return Ok(first);
+        }
+        Ok(Self::alloc(arena, AndOr{first, rest}))
+    }
+
+    fn compile_pipeline<'a>(arena: &'a Bump, lexer: &mut Lexer) -> InterpreterResult<ArenaStatement<'a>> {
+        // The reserved words `!` and `time [-p]` may prefix a pipeline in any order.
+        let (mut negated, mut timed) = (false, None);
+        loop {
+            match lexer.peek().map(|token| token.text.as_str()) {
+                Some("!") => negated = !negated,
+                Some("time") if timed.is_none() => {
+                    lexer.next();
+                    timed = Some(lexer.next_if(|token| token.text == "-p").is_some());
+                    continue;
+                }
+                _ => break
+            }
+            lexer.next();
+        }
+        let mut statement = match lexer.peek() {
+            Some(token) if !lexer::is_control_operator(&token.text) => Self::compile_piped(arena, lexer)?,
+            _ if negated || timed.is_some() => Self::alloc(arena, Noop{}),
+            _ => Self::compile_piped(arena, lexer)?
+        };
+        if negated {
+            statement = Self::alloc(arena, Not{pipeline: statement});
+        }
+        if let Some(posix) = timed {
+            statement = Self::alloc(arena, Time{pipeline: statement, posix});
+        }
+        Ok(statement)
+    }
+
+    fn compile_piped<'a>(arena: &'a Bump, lexer: &mut Lexer) -> InterpreterResult<ArenaStatement<'a>> {
+        let mut words = vec![];
+        let mut stages = vec![Self::compile_command(arena, lexer, &mut words)?];
+        while let Some("|") = lexer.peek().map(|token| token.text.as_str()) {
+            words.extend(lexer.next().map(|token| token.text));
+            stages.push(Self::compile_command(arena, lexer, &mut words)?);
+        }
+        Ok(Self::alloc(arena, Pipeline{stages, command: words.join(" ")}))
+    }
+
+    /// Compiles a single command, attributing any errors it raises to its span of input. Its
+    /// words are added to `words`.
+    fn compile_command<'a>(arena: &'a Bump, lexer: &mut Lexer, words: &mut Vec<String>) -> InterpreterResult<ArenaStatement<'a>> {
+        let tokens = Self::command_tokens(lexer);
+        words.extend(tokens.iter().map(|token| token.text.clone()));
+        let span = match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => first.span.join(last.span),
+            _ => return Err(match lexer.peek() {
+                Some(token) => InterpreterError::Syntax{message: format!("syntax error near unexpected token `{}'", token.text), span: Some(token.span)},
+                None => InterpreterError::syntax("unexpected EOF")
+            })
+        };
+        let statement = Self::compile_expression(arena, tokens).map_err(|err| err.or_span(span))?;
+        Ok(Self::alloc(arena, Spanned{statement, span}))
+    }
+
+    fn command_tokens(lexer: &mut Lexer) -> Vec<Token> {
+        let mut tokens = vec![];
+        // `&&` and `||` inside `[[ ... ]]` belong to the conditional expression.
+        let mut conditional = false;
+        while let Some(token) = lexer.peek() {
+            match token.text.as_str() {
+                "[[" if tokens.is_empty() => conditional = true,
+                "]]" => conditional = false,
+                token if !conditional && lexer::is_control_operator(token) => break,
+                _ => {}
+            }
+            tokens.extend(lexer.next());
+        }
+        tokens
+    }
+
+    fn compile_expression<'a>(arena: &'a Bump, tokens: Vec<Token>) -> InterpreterResult<ArenaStatement<'a>> {
+        let spans: Vec<Span> = tokens.iter().map(|token| token.span).collect();
+        let tokens: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        match tokens.as_slice() {
+            [] => {
+                return Err(InterpreterError::syntax("unexpected EOF"));
+            },
+            ["[[", condition@.., "]]"] => {
+                return Ok(Self::alloc(arena, Conditional::new(condition)?));
+            },
+            ["[[", ..] => {
+                return Err(InterpreterError::syntax("unexpected EOF while looking for `]]'"));
+            },
+            _ => {}
+        }
+        // The redirections of `exec` outlast it.
+        let prefix = tokens.iter().take_while(|token| assignment(token).is_some()).count();
+        if let ["exec", args@..] = &tokens[prefix..] {
+            let statement = Self::alloc(arena, Exec::new(args, &spans[prefix + 1..])?);
+            return Ok(Assigned::wrap(arena, &tokens[..prefix], &spans, statement, true));
+        }
+        let (words, spans, redirects) = FdRedirect::parse(&tokens, &spans)?;
+        let statement = Self::compile_simple(arena, words, &spans)?;
+        if redirects.is_empty() {
+            return Ok(statement);
+        }
+        Ok(Self::alloc(arena, Redirect{statement, redirects}))
+    }
+
+    /// Compiles a command whose redirections have been taken out of its words. Assignments
+    /// written before a builtin are made around it.
+    fn compile_simple<'a>(arena: &'a Bump, words: Vec<String>, spans: &[Span]) -> InterpreterResult<ArenaStatement<'a>> {
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let prefix = words.iter().take_while(|word| assignment(word).is_some()).count();
+        let statement = match &words[prefix..] {
+            [arithmetic] if arithmetic.starts_with("((") && arithmetic.ends_with("))") => {
+                Self::alloc(arena, Arithmetic::new(arithmetic))
+            },
+            ["test", args@..] => {
+                Self::alloc(arena, Test::new(args, false))
+            },
+            ["[", args@..] => {
+                Self::alloc(arena, Test::new(args, true))
+            },
+            ["cd", args@..] =>  {
+                Self::alloc(arena, CD::new(args))
+            },
+            ["pwd", args@..] =>  {
+                Self::alloc(arena, Pwd::new(args))
+            },
+            ["pushd", args@..] =>  {
+                Self::alloc(arena, Pushd::new(args))
+            },
+            ["popd", args@..] =>  {
+                Self::alloc(arena, Popd::new(args))
+            },
+            ["dirs", args@..] =>  {
+                Self::alloc(arena, Dirs::new(args))
+            },
+            ["export", kvs@..] => Self::alloc(arena, Export::new(kvs)),
+            ["set", args@..] => Self::alloc(arena, Set::new(args)),
+            ["jobs", args@..] => Self::alloc(arena, Jobs::new(args)),
+            ["fg", args@..] => Self::alloc(arena, Fg::new(args)),
+            ["bg", args@..] => Self::alloc(arena, Bg::new(args)),
+            ["kill", args@..] => Self::alloc(arena, Kill::new(args)),
+            ["trap", args@..] => Self::alloc(arena, Trap::new(args)),
+            ["exit", args@..] => Self::alloc(arena, Exit::new(args)),
+            ["source" | ".", args@..] => Self::alloc(arena, Source::new(args)),
+            ["alias", args@..] => Self::alloc(arena, Alias::new(args)),
+            ["unalias", args@..] => Self::alloc(arena, Unalias::new(args)),
+            ["return", args@..] => Self::alloc(arena, Return::new(args)),
+            _ => {
+                return Ok(Self::alloc(arena, Command::new(words).with_spans(spans)));
+            }
+        };
+        let special = matches!(words.get(prefix), Some(&("export" | "set" | "trap" | "exit" | "source" | "." | "return")));
+        Ok(Assigned::wrap(arena, &words[..prefix], spans, statement, special))
+    }
+
+    fn alloc<'a, T: Statement + 'a>(arena: &'a Bump, val: T) -> ArenaStatement<'a> {
+        let statement: &'a mut (dyn Statement + 'a) = arena.alloc(val);
+        // SAFETY: the statement was just allocated in the arena and nothing else refers to it.
+        unsafe { bumpalo::boxed::Box::from_raw(statement) }
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures an `Interpreter` before it is created. Unless told otherwise, the interpreter
+/// inherits the process's environment and working directory.
+#[derive(Default)]
+pub struct InterpreterBuilder {
+    environment: Option<Environment>,
+    vars: Vec<(String, String)>,
+    cwd: Option<PathBuf>,
+    name: Option<String>,
+    args: Vec<String>,
+    builtins: Vec<(String, Box<dyn Builtin>)>,
+    spawner: Option<Box<dyn Spawner>>,
+    options: Vec<(String, bool)>
+}
+
+impl InterpreterBuilder {
+    /// Replaces the inherited environment with `vars`.
+    pub fn env<K: ToString, V: ToString, I: IntoIterator<Item=(K, V)>>(mut self, vars: I) -> Self {
+        self.environment = Some(vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        self
+    }
+
+    /// Sets a single variable on top of the initial environment.
+    pub fn var<K: ToString, V: ToString>(mut self, name: K, value: V) -> Self {
+        self.vars.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the initial working directory. Relative paths are resolved against the process's.
+    pub fn cwd<P: Into<PathBuf>>(mut self, cwd: P) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Sets `$0`.
+    pub fn name<S: ToString>(mut self, name: S) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Sets the positional parameters `$1`, `$2`, ....
+    pub fn args<S: ToString, I: IntoIterator<Item=S>>(mut self, args: I) -> Self {
+        self.args = args.into_iter().map(|arg| arg.to_string()).collect();
+        self
+    }
+
+    /// Registers a builtin, as `Interpreter::register_builtin` does.
+    pub fn builtin<S: ToString, B: Builtin + 'static>(mut self, name: S, builtin: B) -> Self {
+        self.builtins.push((name.to_string(), Box::new(builtin)));
+        self
+    }
+
+    /// Starts external commands with `spawner` instead of as real processes.
+    pub fn spawner<S: Spawner + 'static>(mut self, spawner: S) -> Self {
+        self.spawner = Some(Box::new(spawner));
+        self
+    }
+
+    /// Turns the shell option `name` on or off, as `set -o name` and `set +o name` do.
+    pub fn option<S: ToString>(mut self, name: S, enabled: bool) -> Self {
+        self.options.push((name.to_string(), enabled));
+        self
+    }
+
+    pub fn build(self) -> InterpreterResult<Interpreter> {
+        let cwd = match self.cwd {
+            Some(cwd) => std::path::absolute(cwd)?,
+            None => std::env::current_dir()?
+        };
+        let mut p = CDProcess{ target: cwd.clone(), result: None };
+        if let Err(err) = p.enter() {
+            return Err(InterpreterError::runtime(format!("{}: {}", cwd.display(), physical::strerror(&err))));
+        }
+        let mut environment = self.environment.unwrap_or_else(|| std::env::vars().collect());
+        environment.extend(self.vars);
+        if let Some(name) = self.name {
+            environment.insert("0".to_string(), name);
+        }
+        substitution::set_positional(&self.args, &mut environment);
+        let mut interpreter = Interpreter::with(environment, physical::logical("/", cwd));
+        interpreter.builtins.extend(self.builtins);
+        if let Some(spawner) = self.spawner {
+            interpreter.spawner = spawner;
+        }
+        for (name, enabled) in self.options {
+            if !interpreter.set_option(&name, enabled) {
+                return Err(InterpreterError::runtime(format!("{}: invalid option name", name)));
+            }
+        }
+        Ok(interpreter)
+    }
+}
+
+/// Splits leading single letter options, such as `-L` or `-LP`, from a builtin's operands.
+/// Options are returned in the order given so that the last of conflicting options wins.
+fn options<'a>(name: &str, args: &'a [String], allowed: &str) -> InterpreterResult<(Vec<char>, &'a [String])> {
+    let mut flags = vec![];
+    let mut operands = args;
+    while let Some(arg) = operands.first() {
+        if arg == "--" {
+            operands = &operands[1..];
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            break;
+        }
+        for flag in arg.chars().skip(1) {
+            if !allowed.contains(flag) {
+                return Err(InterpreterError::runtime(format!("{}: -{}: invalid option", name, flag)));
+            }
+            flags.push(flag);
+        }
+        operands = &operands[1..];
+    }
+    Ok((flags, operands))
+}
+
+/// Splits a `name=value` word into its name and raw value, if the name is a valid identifier.
+fn assignment(token: &str) -> Option<(&str, &str)> {
+    let idx = token.find('=')?;
+    let name = &token[..idx];
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return None
+    }
+    if !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((name, &token[idx + 1..]))
+}
+
+/// A builtin run with the variable assignments written before it. As in POSIX, they last
+/// only while it runs, except before a special builtin such as `export` or `exec`.
+struct Assigned<'a> {
+    assignments: Vec<String>,
+    spans: Vec<Span>,
+    statement: ArenaStatement<'a>,
+    special: bool
+}
+
+impl<'a> Assigned<'a> {
+    /// Wraps `statement` in its `assignments`, whose spans begin `spans`, if it has any.
+    fn wrap<S: ToString>(arena: &'a Bump, assignments: &[S], spans: &[Span], statement: ArenaStatement<'a>, special: bool) -> ArenaStatement<'a> {
+        if assignments.is_empty() {
+            return statement;
+        }
+        let assignments: Vec<String> = assignments.iter().map(S::to_string).collect();
+        let spans = spans.iter().take(assignments.len()).copied().collect();
+        Interpreter::alloc(arena, Assigned{assignments, spans, statement, special})
+    }
+}
+
+impl Statement for Assigned<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let mut values = vec![];
+        for (index, (name, value)) in self.assignments.iter().filter_map(|token| assignment(token)).enumerate() {
+            let value = substitution::tilde(value, true, env);
+            let value = substitution(value, env, context).map_err(|err| match self.spans.get(index) {
+                Some(&span) => err.or_span(span),
+                None => err
+            })?;
+            values.push((name.to_string(), value));
+        }
+        let saved: Vec<(String, Option<String>)> = values.into_iter()
+            .map(|(name, value)| (name.clone(), shell.environment.insert(name, value)))
+            .collect();
+        let result = self.statement.eval(shell);
+        if !self.special {
+            for (name, value) in saved.into_iter().rev() {
+                match value {
+                    Some(value) => shell.environment.insert(name, value),
+                    None => shell.environment.remove(&name)
+                };
+            }
+        }
+        result
+    }
+
+    fn in_process(&self, shell: &Interpreter) -> bool {
+        self.statement.in_process(shell)
+    }
+}
+
+struct Noop {}
+
+impl Statement for Noop {
+    fn eval(&mut self, _: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> { Ok(Box::new(Noop{})) }}
+
+impl Process for Noop {
+    fn wait(&mut self) -> ExitStatus { ExitStatus::SUCCESS }
+}
+
+/// Shell variables by name. Array elements other than the first are stored as `NAME[i]`.
+pub type Environment = HashMap<String, String>;
+
+struct Command {
+    tokens: Vec<String>,
+    spans: Vec<Span>
+}
+
+impl Command {
+    fn new<S: ToString, T: IntoIterator<Item=S>>(tokens: T) -> Command {
+        let tokens = tokens.into_iter().map(|i| i.to_string()).collect();
+        Command{tokens, spans: vec![]}
+    }
+
+    fn with_spans(mut self, spans: &[Span]) -> Command {
+        self.spans = spans.iter().take(self.tokens.len()).copied().collect();
+        self
+    }
+
+    /// Attributes an error raised while expanding or running the word at `index` to it.
+    fn locate(&self, index: usize) -> impl Fn(InterpreterError) -> InterpreterError + '_ {
+        move |err| match self.spans.get(index) {
+            Some(&span) => err.or_span(span),
+            None => err
+        }
+    }
+}
+
+impl Statement for Command {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let mut assignments = Environment::new();
+        let mut traced = vec![];
+        let mut iter = self.tokens.iter().enumerate().peekable();
+        while let Some((index, (name, value))) = iter.peek().and_then(|(index, token)| Some((*index, assignment(token)?))) {
+            let value = substitution::tilde(value, true, env);
+            let value = substitution(value, env, context).map_err(self.locate(index))?;
+            traced.push(format!("{}={}", name, traced_word(&value)));
+            assignments.insert(name.to_string(), value);
+            iter.next();
+        }
+        let mut expanded = vec![];
+        let mut command = None;
+        for (index, token) in iter {
+            command.get_or_insert(index);
+            expanded.extend(fields(token, env, context).map_err(self.locate(index))?);
+        }
+        if shell.options.xtrace {
+            traced.extend(expanded.iter().map(|word| traced_word(word)));
+            shell.trace(&traced);
+        }
+        let env = &mut shell.environment;
+        let program = match expanded.first() {
+            Some(program) => program.clone(),
+            None => {
+                // A bare assignment, or a command that expanded to nothing, sets shell
+                // variables rather than a command's environment.
+                env.extend(assignments);
+                return Ok(Box::new(Noop{}));
+            }
+        };
+        if shell.builtins.contains_key(&program) {
+            // A builtin writing to a pipe is forked like any other stage that runs in the shell.
+            return match shell.piping {
+                Some(next) => Ok(Box::new(Pipeline::fork(shell, next, |shell| {
+                    let result = Self::builtin(shell, &expanded, assignments);
+                    shell.status(result)
+                })?)),
+                None => Self::builtin(shell, &expanded, assignments)
+            };
+        }
+        let request = Self::request(shell, expanded, assignments)?;
+        let locate = self.locate(command.unwrap_or_default());
+        shell.spawner.spawn(request).map_err(|err| locate(Self::spawn_error(program, err)))
+    }
+
+    /// Whether a command runs a builtin is only known once its words are expanded, so a
+    /// command forks a builtin itself when it writes to a pipe. Only bare assignments are
+    /// known to run in the shell.
+    fn in_process(&self, _shell: &Interpreter) -> bool {
+        self.tokens.iter().all(|token| assignment(token).is_some())
+    }
+}
+
+/// Quotes a word for `xtrace` if it would not otherwise read back as a single word.
+fn traced_word(word: &str) -> String {
+    match word.is_empty() || word.contains(|c: char| !c.is_ascii_alphanumeric() && !"-_./=:,+%@^".contains(c)) {
+        true => substitution::quote(word),
+        false => word.to_string()
+    }
+}
+
+impl Command {
+    /// Prepares to start `argv` as an external command, which is given the exported
+    /// variables with `assignments` on top, and the interpreter's streams and descriptors.
+    fn request(shell: &Interpreter, argv: Vec<String>, assignments: Environment) -> io::Result<SpawnRequest> {
+        let mut env: Environment = shell.environment.iter()
+            .filter(|(key, _)| substitution::is_variable_name(key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        env.extend(assignments);
+        let stdin = Streams::stream(&shell.streams.stdin)?;
+        let stdout = Streams::stream(&shell.streams.stdout)?;
+        let stderr = Streams::stream(&shell.streams.stderr)?;
+        let fds = shell.fds.iter().map(|(&target, file)| (target, file.as_raw_fd())).collect();
+        Ok(SpawnRequest{ argv, env, cwd: shell.cwd.clone(), stdin, stdout, stderr, process_group: shell.process_group, fds })
+    }
+
+    /// Describes why the external command `program` could not be started.
+    fn spawn_error(program: String, err: io::Error) -> InterpreterError {
+        match err.kind() {
+            io::ErrorKind::NotFound => InterpreterError::CommandNotFound{command: program, span: None},
+            io::ErrorKind::PermissionDenied => InterpreterError::PermissionDenied{command: program, span: None},
+            _ => err.into()
+        }
+    }
+
+    /// Runs the registered builtin named by `args` in-process on the interpreter's streams.
+    /// Prefix assignments are visible to the builtin only.
+    fn builtin(shell: &mut Interpreter, args: &[String], assignments: Environment) -> InterpreterResult<Box<dyn Process>> {
+        let (builtins, env, streams) = (&mut shell.builtins, &mut shell.environment, &shell.streams);
+        let Some(builtin) = args.first().and_then(|name| builtins.get_mut(name)) else {
+            return Ok(Box::new(CompletedProcess{ result: ExitStatus::FAILURE }));
+        };
+        let saved: Vec<(String, Option<String>)> = assignments.keys().map(|name| (name.clone(), env.get(name).cloned())).collect();
+        env.extend(assignments);
+        let (mut stdin, mut stdout, mut stderr) = (streams.stdin(), streams.stdout(), streams.stderr());
+        let result = builtin.run(args, env, &mut BuiltinStdio{ stdin: &mut stdin, stdout: &mut stdout, stderr: &mut stderr });
+        stdout.flush()?;
+        for (name, value) in saved {
+            match value {
+                Some(value) => env.insert(name, value),
+                None => env.remove(&name)
+            };
+        }
+        Ok(Box::new(CompletedProcess{ result }))
+    }
+}
+
+/// `export [name[=value] ...]`, which sets variables, or without any, or with `-p`, lists
+/// the variables that commands are given in a form that sets them again.
+struct Export {
+    pairs: Vec<String>
+}
+
+impl Export {
+    fn new<S: ToString>(pairs: &[S]) -> Export {
+        Export{pairs: pairs.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Export {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        if self.pairs.is_empty() || self.pairs == ["-p"] {
+            let mut names: Vec<&String> = shell.environment.keys().filter(|name| substitution::is_variable_name(name)).collect();
+            names.sort();
+            for name in names {
+                shell.print_line(format!("export {}={}", name, substitution::quote(&shell.environment[name])));
+            }
+            return Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }));
+        }
+        let context = shell.context();
+        let env = &mut shell.environment;
+        for pair in &self.pairs {
+            if pair.starts_with('=') {
+                return Err(InterpreterError::runtime(format!("export: '{}`: not a valid identifier", pair)))
+            }
+            let mut kv = pair.splitn(2, '=');
+            let name = kv.next().unwrap_or_default().to_string();
+            let value = substitution::tilde(kv.next().unwrap_or_default(), true, env);
+            let value = substitution(value, env, context)?;
+            env.insert(name, value);
+        }
+        Ok(Box::new(Noop{}))
+    }
+}
+
+struct Arithmetic {
+    expression: String
+}
+
+impl Arithmetic {
+    fn new(command: &str) -> Arithmetic {
+        Arithmetic{expression: command[2..command.len() - 2].to_string()}
+    }
+}
+
+impl Statement for Arithmetic {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let expression = substitution(&self.expression, env, context)?;
+        let value = arithmetic::evaluate(expression, env)?;
+        Ok(Box::new(CompletedProcess{ result: (value != 0).into() }))
+    }
+}
+
+/// `test expression` or `[ expression ]`, which exit with 0 if the expression is true, 1 if
+/// it is false and 2 if it cannot be evaluated.
+struct Test {
+    args: Vec<String>,
+    /// Whether the command was `[`, whose last argument must be `]` once expanded.
+    bracket: bool
+}
+
+impl Test {
+    fn new<S: ToString>(args: &[S], bracket: bool) -> Test {
+        Test{args: args.iter().map(S::to_string).collect(), bracket}
+    }
+}
+
+impl Statement for Test {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, env, context)?);
+        }
+        let result = match args.last() {
+            Some(last) if self.bracket && last == "]" => conditional::test(&args[..args.len() - 1], &shell.cwd),
+            _ if self.bracket => Err(InterpreterError::runtime("[: missing `]'")),
+            _ => conditional::test(&args, &shell.cwd)
+        };
+        let result = result.map(ExitStatus::from).unwrap_or_else(|err| {
+            shell.print_error(err);
+            ExitStatus::new(2)
+        });
+        Ok(Box::new(CompletedProcess{ result }))
+    }
+}
+
+struct Conditional {
+    condition: conditional::Condition
+}
+
+impl Conditional {
+    fn new<S: AsRef<str>>(tokens: &[S]) -> InterpreterResult<Conditional> {
+        Ok(Conditional{condition: conditional::Condition::parse(tokens)?})
+    }
+}
+
+impl Statement for Conditional {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let result = self.condition.eval(env, context, &shell.cwd).unwrap_or_else(|err| {
+            shell.unbound(&err);
+            shell.report(&err);
+            false
+        });
+        Ok(Box::new(CompletedProcess{ result: result.into() }))
+    }
+}
+
+/// Attributes errors from a command to the span of input it was compiled from.
+struct Spanned<'a> {
+    statement: ArenaStatement<'a>,
+    span: Span
+}
+
+impl Statement for Spanned<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        if shell.options.noexec && !shell.interactive() {
+            return Ok(Box::new(Noop{}));
+        }
+        self.statement.eval(shell).map_err(|err| {
+            shell.unbound(&err);
+            err.or_span(self.span)
+        })
+    }
+
+    fn in_process(&self, shell: &Interpreter) -> bool {
+        self.statement.in_process(shell)
+    }
+}
+
+/// Commands separated by `;` or newlines, run one after the other. The list is kept flat
+/// so that no script is too long to run.
+struct Sequence<'a> {
+    statements: Vec<ArenaStatement<'a>>
+}
+
+impl Statement for Sequence<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let Some((last, init)) = self.statements.split_last_mut() else {
+            return Ok(Box::new(Noop{}));
+        };
+        for statement in init {
+            let result = statement.eval(shell);
+            let status = shell.status(result);
+            if shell.cancelled() {
+                return Ok(Box::new(CompletedProcess{ result: status }));
+            }
+        }
+        last.eval(shell)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Connective {
+    And,
+    Or
+}
+
+/// Pipelines joined by `&&` and `||`, which associate to the left: each runs only if the
+/// status so far succeeded, after `&&`, or failed, after `||`. Every status but that of the
+/// last pipeline is tested, so it neither raises `ERR` nor exits under `errexit`.
+struct AndOr<'a> {
+    first: ArenaStatement<'a>,
+    rest: Vec<(Connective, ArenaStatement<'a>)>
+}
+
+impl AndOr<'_> {
+    fn runs(connective: Connective, status: ExitStatus, shell: &Interpreter) -> bool {
+        let wanted = match connective {
+            Connective::And => true,
+            Connective::Or => false
+        };
+        status.success() == wanted && !shell.cancelled()
+    }
+}
+
+impl Statement for AndOr<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let Some(((connective, last), init)) = self.rest.split_last_mut() else {
+            return self.first.eval(shell);
+        };
+        shell.testing += 1;
+        let result = self.first.eval(shell);
+        let mut status = shell.status(result);
+        for (connective, pipeline) in init {
+            if Self::runs(*connective, status, shell) {
+                let result = pipeline.eval(shell);
+                status = shell.status(result);
+            }
+        }
+        shell.testing -= 1;
+        if Self::runs(*connective, status, shell) {
+            return last.eval(shell);
+        }
+        Ok(Box::new(CompletedProcess{ result: status }))
+    }
+}
+
+/// `! pipeline`, which inverts the status of the pipeline.
+struct Not<'a> {
+    pipeline: ArenaStatement<'a>
+}
+
+impl Statement for Not<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        shell.testing += 1;
+        let result = self.pipeline.eval(shell);
+        let status = shell.status(result);
+        shell.testing -= 1;
+        shell.last_status = (!status.success()).into();
+        Ok(Box::new(CompletedProcess{ result: shell.last_status }))
+    }
+}
+
+/// `time [-p] pipeline`, which reports the real, user and system time the pipeline took.
+struct Time<'a> {
+    pipeline: ArenaStatement<'a>,
+    posix: bool
+}
+
+impl Statement for Time<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let start = Instant::now();
+        let (user, sys) = physical::cpu_times();
+        let result = self.pipeline.eval(shell);
+        let status = shell.status(result);
+        let (real, (end_user, end_sys)) = (start.elapsed(), physical::cpu_times());
+        shell.print_error(format_times(real, end_user.saturating_sub(user), end_sys.saturating_sub(sys), self.posix));
+        Ok(Box::new(CompletedProcess{ result: status }))
+    }
+}
+
+/// Formats the report of `time`: bash's default format, or the POSIX one for `time -p`.
+fn format_times(real: Duration, user: Duration, sys: Duration, posix: bool) -> String {
+    let times = [("real", real), ("user", user), ("sys", sys)];
+    let lines: Vec<String> = times.iter().map(|(name, time)| {
+        let millis = time.as_millis();
+        if posix {
+            format!("{} {}.{:02}", name, millis / 1000, millis % 1000 / 10)
+        } else {
+            format!("{}\t{}m{}.{:03}s", name, millis / 60_000, millis / 1000 % 60, millis % 1000)
+        }
+    }).collect();
+    if posix { lines.join("\n") } else { format!("\n{}", lines.join("\n")) }
+}
+
+/// Commands joined by `|`. Every stage is started before any is waited for, each reading
+/// and writing its own end of the OS pipes between them, and all of them are reaped with
+/// their statuses recorded in `PIPESTATUS`. Under job control the stages share a process
+/// group of their own, which has the terminal while the pipeline runs. Otherwise the shell
+/// ignores SIGINT and SIGQUIT until the pipeline finishes, so that a Ctrl-C sent to the
+/// whole process group kills only the stages.
+struct Pipeline<'a> {
+    stages: Vec<ArenaStatement<'a>>,
+    /// The pipeline as it is shown in the job table.
+    command: String
+}
+
+impl Pipeline<'_> {
+    /// Starts each stage with the interpreter's streams pointed at its pipes. Stages that run
+    /// in the shell are forked, except for the last, so that they cannot stall on a full pipe
+    /// and cannot change the shell's own state. The last stage runs in the shell itself, as
+    /// in ksh and zsh, so that `echo x | cd /` does change the shell's directory.
+    fn start(&mut self, shell: &mut Interpreter, outer: &(Option<File>, Option<File>), processes: &mut Vec<Box<dyn Process>>) -> InterpreterResult<()> {
+        let last = self.stages.len() - 1;
+        let mut reader = outer.0.as_ref().map(File::try_clone).transpose()?;
+        for (idx, stage) in self.stages.iter_mut().enumerate() {
+            let (next, writer) = if idx < last {
+                let (reader, writer) = io::pipe()?;
+                (Some(File::from(OwnedFd::from(reader))), Some(File::from(OwnedFd::from(writer))))
+            } else {
+                (None, outer.1.as_ref().map(File::try_clone).transpose()?)
+            };
+            // Traps run on the pipeline's own streams, so that their output is not piped.
+            let clone = |file: &Option<File>| file.as_ref().map(File::try_clone).transpose();
+            (shell.streams.stdin, shell.streams.stdout) = (clone(&outer.0)?, clone(&outer.1)?);
+            shell.safe_point();
+            shell.fire(Condition::Debug);
+            shell.streams.stdin = reader;
+            shell.streams.stdout = writer;
+            shell.piping = next.as_ref().map(AsRawFd::as_raw_fd);
+            let process: Box<dyn Process> = match shell.piping {
+                Some(next) if stage.in_process(shell) => Box::new(Self::fork(shell, next, |shell| {
+                    let result = stage.eval(shell);
+                    shell.status(result)
+                })?),
+                _ => match stage.eval(shell) {
+                    Ok(process) => process,
+                    // A lone command's error is the caller's to report.
+                    Err(err) if last == 0 => return Err(err),
+                    Err(err) => {
+                        shell.report(&err);
+                        Box::new(CompletedProcess{ result: ExitStatus::new(err.exit_code()) })
+                    }
+                }
+            };
+            if let (Some(group), Some(pid)) = (shell.process_group.as_mut(), process.pid()) {
+                jobs::join(pid, group);
+            }
+            processes.push(process);
+            // Only the stages themselves may keep the pipes open. A lone stage has no pipes,
+            // and keeps whatever streams `exec` left it.
+            if last > 0 {
+                shell.streams.stdin = None;
+                shell.streams.stdout = None;
+            }
+            reader = next;
+        }
+        Ok(())
+    }
+
+    /// Runs `stage` in a forked copy of the shell, as a stage that writes to the pipe whose
+    /// read end is `next`.
+    fn fork<F: FnOnce(&mut Interpreter) -> ExitStatus>(shell: &mut Interpreter, next: RawFd, stage: F) -> io::Result<ForkedProcess> {
+        physical::fork(|| {
+            // Holding the read end of its own output would keep the stage from seeing that
+            // the next stage has exited.
+            unsafe { libc::close(next) };
+            shell.piping = None;
+            // The stage joins the job, but has no job control of its own.
+            if let Some(group) = shell.process_group.take() {
+                let _ = jobs::enter(&group);
+            }
+            shell.options.monitor = false;
+            shell.jobs.detach();
+            shell.traps.reset();
+            stage(shell)
+        })
+    }
+}
+
+impl Statement for Pipeline<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let _ignored = signals::ignore(&signals::FOREGROUND);
+        let outer = (shell.streams.stdin.take(), shell.streams.stdout.take());
+        let mut processes = Vec::with_capacity(self.stages.len());
+        if shell.options.monitor {
+            shell.process_group = Some(ProcessGroup{ pgid: 0, terminal: shell.jobs.terminal() });
+        }
+        let started = self.start(shell, &outer, &mut processes);
+        shell.piping = None;
+        let group = shell.process_group.take();
+        if self.stages.len() > 1 || started.is_err() {
+            (shell.streams.stdin, shell.streams.stdout) = outer;
+        }
+        let statuses = match group {
+            // No stage was a child process if nobody leads the group.
+            Some(group) if group.pgid != 0 => {
+                let (statuses, stopped) = shell.jobs.foreground(Job::new(group.pgid, self.command.clone(), processes), None);
+                if let Some(line) = stopped {
+                    shell.print_error(line);
+                }
+                statuses
+            }
+            _ => processes.iter_mut().map(|process| process.wait()).collect()
+        };
+        if let Err(err) = &started {
+            // A command that could not run still leaves its status behind.
+            shell.pipeline_status(&[ExitStatus::new(err.exit_code())]);
+        }
+        started?;
+        let status = shell.pipeline_status(&statuses);
+        if !status.success() {
+            shell.failed(status);
+        }
+        shell.safe_point();
+        Ok(Box::new(CompletedProcess{ result: status }))
+    }
+}
+
+/// A command run with some of its descriptors redirected, which are the shell's own again
+/// once it has started.
+struct Redirect<'a> {
+    statement: ArenaStatement<'a>,
+    redirects: Vec<FdRedirect>
+}
+
+/// A redirection of one of the descriptors of a command, or of the shell with `exec`: the
+/// descriptor it sets, and the file to open on it, or `&n` to duplicate descriptor `n`, or
+/// `&-` to close it.
+struct FdRedirect {
+    fd: RawFd,
+    _type: RedirectType,
+    target: String
+}
+
+impl FdRedirect {
+    /// Separates the redirections among the tokens of a command from its words, which are
+    /// returned with their spans.
+    fn parse<S: AsRef<str>>(tokens: &[S], spans: &[Span]) -> InterpreterResult<(Vec<String>, Vec<Span>, Vec<FdRedirect>)> {
+        let (mut words, mut word_spans, mut redirects): (Vec<String>, Vec<Span>, _) = (vec![], vec![], vec![]);
+        // Whether the last word came right before the current token.
+        let mut previous = false;
+        let mut tokens = tokens.iter().map(AsRef::as_ref).zip(spans).peekable();
+        while let Some((token, span)) = tokens.next() {
+            let (fd, _type) = match token {
+                ">" | ">>" | ">|" | "<" | "<&" => {
+                    // A number written right against the operator names the descriptor.
+                    let fd = match words.last() {
+                        Some(word) if previous && !word.is_empty() && word.chars().all(|c| c.is_ascii_digit()) => {
+                            let fd = word.parse::<RawFd>().map_err(|_| InterpreterError::Redirection{
+                                target: word.clone(), error: io::Error::from_raw_os_error(libc::EBADF), span: word_spans.last().copied()
+                            })?;
+                            words.pop();
+                            word_spans.pop();
+                            fd
+                        }
+                        _ if token.starts_with('<') => 0,
+                        _ => 1
+                    };
+                    let _type = match token {
+                        ">" => RedirectType::Truncate,
+                        ">>" => RedirectType::Append,
+                        ">|" => RedirectType::Clobber,
+                        _ => RedirectType::Read
+                    };
+                    (fd, _type)
+                }
+                _ => {
+                    words.push(token.to_string());
+                    word_spans.push(*span);
+                    previous = tokens.peek().is_some_and(|(_, next)| next.start == span.end);
+                    continue;
+                }
+            };
+            let target = match tokens.next() {
+                Some((target, span)) if lexer::is_operator(target) => {
+                    return Err(InterpreterError::Syntax{message: format!("syntax error near unexpected token `{}'", target), span: Some(*span)});
+                }
+                // `<&n` duplicates descriptor `n` as `<` with a target of `&n` does.
+                Some((target, _)) if token == "<&" => format!("&{}", target),
+                Some((target, _)) => target.to_string(),
+                None => return Err(InterpreterError::syntax("unexpected EOF"))
+            };
+            redirects.push(FdRedirect{ fd, _type, target });
+            previous = false;
+        }
+        Ok((words, word_spans, redirects))
+    }
+
+    /// Opens or duplicates the file the descriptor is redirected to, or returns `None` if it
+    /// is closed.
+    fn file(&self, shell: &mut Interpreter) -> InterpreterResult<Option<File>> {
+        match self.target.strip_prefix('&') {
+            Some("-") => Ok(None),
+            Some(fd) => Ok(Some(shell.duplicate(fd)?)),
+            None => Ok(Some(self._type.open(shell, &self.target)?))
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RedirectType {
+    Append,
+    Truncate,
+    /// `>|`, which truncates the file even under `noclobber`.
+    Clobber,
+    /// `<`, which opens the file for reading.
+    Read
+}
+
+impl RedirectType {
+    /// Expands the target of a redirection and opens it.
+    fn open(self, shell: &mut Interpreter, target: &str) -> InterpreterResult<File> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let target = substitution(substitution::tilde(target, false, env), env, context)?;
+        let path = physical::resolve(&shell.cwd, &target);
+        let mut opts = OpenOptions::new();
+        match self {
+            RedirectType::Append => opts.write(true).create(true).append(true),
+            RedirectType::Truncate | RedirectType::Clobber => opts.write(true).create(true).truncate(true),
+            RedirectType::Read => opts.read(true)
+        };
+        // `noclobber` protects regular files only, so that `> /dev/null` still works.
+        if let RedirectType::Truncate = self {
+            if shell.options.noclobber && std::fs::metadata(&path).is_ok_and(|metadata| metadata.is_file()) {
+                let error = io::Error::new(io::ErrorKind::AlreadyExists, "cannot overwrite existing file");
+                return Err(InterpreterError::Redirection{target, error, span: None});
+            }
+        }
+        opts.open(path).map_err(|error| InterpreterError::Redirection{target, error, span: None})
+    }
+}
+
+impl From<RedirectType> for bool {
+    fn from(_type: RedirectType) -> bool {
+        match _type {
+            RedirectType::Append | RedirectType::Read => false,
+            RedirectType::Truncate | RedirectType::Clobber => true
+        }
+    }
+}
+
+/// A statement allocated in the compile arena. Unlike a plain arena reference it is dropped
+/// along with the tree, releasing whatever the statement owns.
+type ArenaStatement<'a> = bumpalo::boxed::Box<'a, dyn Statement + 'a>;
+
+impl Redirect<'_> {
+    /// Redirects the shell's descriptors in order, saving each one's previous file in `saved`.
+    fn apply(&self, shell: &mut Interpreter, saved: &mut Vec<(RawFd, Option<File>)>) -> InterpreterResult<()> {
+        for redirect in &self.redirects {
+            let file = redirect.file(shell)?;
+            saved.push((redirect.fd, shell.replace_fd(redirect.fd, file)?));
+        }
+        Ok(())
+    }
+}
+
+impl Statement for Redirect<'_> {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let mut saved = Vec::with_capacity(self.redirects.len());
+        let result = self.apply(shell, &mut saved).and_then(|_| self.statement.eval(shell));
+        for (fd, previous) in saved.into_iter().rev() {
+            shell.restore_fd(fd, previous);
+        }
+        result
+    }
+
+    fn in_process(&self, shell: &Interpreter) -> bool {
+        self.statement.in_process(shell)
+    }
+}
+
+struct CD {
+    args: Vec<String>,
+}
+
+impl CD {
+    fn new<S: ToString>(args: &[S]) -> CD {
+        CD{args: args.iter().map(S::to_string).collect()}
+    }
+
+    /// Determines the directory to change to and whether it should be printed afterwards.
+    fn target(operands: &[String], env: &Environment, cwd: &Path) -> InterpreterResult<(PathBuf, bool)> {
+        match operands {
+            [] => match env.get("HOME") {
+                Some(home) if !home.is_empty() => Ok((PathBuf::from(home), false)),
+                _ => Err(InterpreterError::runtime("cd: HOME not set"))
+            },
+            [dir] if dir == "-" => match env.get("OLDPWD") {
+                Some(oldpwd) if !oldpwd.is_empty() => Ok((PathBuf::from(oldpwd), true)),
+                _ => Err(InterpreterError::runtime("cd: OLDPWD not set"))
+            },
+            [dir] => Ok(Self::search(dir, env, cwd)),
+            _ => Err(InterpreterError::runtime("cd: too many arguments"))
+        }
+    }
+
+    /// Searches `CDPATH` for relative directories that do not begin with `.` or `..`. The
+    /// result is printed if it was found through a non-empty `CDPATH` entry.
+    fn search(dir: &str, env: &Environment, cwd: &Path) -> (PathBuf, bool) {
+        let path = Path::new(dir);
+        if let (Some(Component::Normal(_)), Some(cdpath)) = (path.components().next(), env.get("CDPATH")) {
+            for entry in cdpath.split(':') {
+                let candidate = Path::new(if entry.is_empty() { "." } else { entry }).join(path);
+                if physical::resolve(cwd, &candidate).is_dir() {
+                    return (candidate, !entry.is_empty());
+                }
+            }
+        }
+        (path.to_path_buf(), false)
+    }
+}
+
+impl Statement for CD {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, env, context)?);
+        }
+        let (flags, operands) = options("cd", &args, "LP")?;
+        let (target, print) = Self::target(operands, env, &shell.cwd)?;
+        let mut p = Self::change(shell, target, flags.last() == Some(&'P'))?;
+        if print && p.wait().success() {
+            shell.print_line(shell.pwd());
+        }
+        Ok(Box::new(p))
+    }
+}
+
+impl CD {
+    /// Changes to `target`, either lexically relative to `PWD` or, if `physical`, by following
+    /// symlinks, and updates `PWD`, `OLDPWD` and `DIRSTACK` to match. If the directory cannot
+    /// be entered the reason is reported and a failed process returned.
+    fn change(shell: &mut Interpreter, target: PathBuf, physical: bool) -> InterpreterResult<CDProcess> {
+        let pwd = match shell.environment.get("PWD") {
+            Some(pwd) if Path::new(pwd).is_absolute() => PathBuf::from(pwd),
+            _ => shell.cwd.clone()
+        };
+        let directory = if physical { physical::resolve(&shell.cwd, target) } else { physical::logical(&pwd, target) };
+        let mut p = CDProcess{ target: directory.clone(), result: None };
+        if let Err(err) = p.enter() {
+            shell.print_error(format!("cd: {}: {}", directory.display(), physical::strerror(&err)));
+            return Ok(p);
+        }
+        let directory = if physical { std::fs::canonicalize(directory)? } else { directory };
+        shell.cwd = directory.clone();
+        shell.environment.insert("OLDPWD".to_string(), pwd.to_string_lossy().to_string());
+        shell.environment.insert("PWD".to_string(), directory.to_string_lossy().to_string());
+        shell.sync_dirstack();
+        Ok(p)
+    }
+}
+
+/// `set [-+]o [name]` and `set [-+]flags`, which turn shell options on and off or list them,
+/// followed by `[--] [arg ...]`, which replace the positional parameters.
+struct Set {
+    args: Vec<String>,
+}
+
+impl Set {
+    fn new<S: ToString>(args: &[S]) -> Set {
+        Set{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Set {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let mut args = args.iter();
+        let mut positional = None;
+        while let Some(arg) = args.next() {
+            let enable = match arg.chars().next() {
+                _ if arg == "--" => {
+                    positional = Some(args.by_ref().collect::<Vec<&String>>());
+                    break;
+                }
+                Some('-') if arg.len() > 1 => true,
+                Some('+') if arg.len() > 1 => false,
+                // The options end at the first operand.
+                _ => {
+                    positional = Some(std::iter::once(arg).chain(args.by_ref()).collect());
+                    break;
+                }
+            };
+            // Flags may be combined, as in `set -eo pipefail`, each `o` taking the next name.
+            for flag in arg.chars().skip(1) {
+                let name = match flag {
+                    'o' => match args.next() {
+                        Some(name) => name.as_str(),
+                        // Without a name, `-o` lists the options and `+o` prints the commands
+                        // that would restore them.
+                        None => {
+                            for (name, enabled) in shell.options.list() {
+                                match enable {
+                                    true => shell.print_line(format!("{:<15}\t{}", name, if enabled { "on" } else { "off" })),
+                                    false => shell.print_line(format!("set {}o {}", if enabled { '-' } else { '+' }, name))
+                                }
+                            }
+                            continue;
+                        }
+                    },
+                    flag => Options::name(flag).ok_or_else(|| InterpreterError::runtime(format!("set: {}{}: invalid option", &arg[..1], flag)))?
+                };
+                if !shell.set_option(name, enable) {
+                    return Err(InterpreterError::runtime(format!("set: {}: invalid option name", name)));
+                }
+            }
+        }
+        if let Some(params) = positional {
+            substitution::set_positional(&params, &mut shell.environment);
+        }
+        Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }))
+    }
+}
+
+/// `jobs [-lp]`, which lists the jobs in the job table.
+struct Jobs {
+    args: Vec<String>,
+}
+
+impl Jobs {
+    fn new<S: ToString>(args: &[S]) -> Jobs {
+        Jobs{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Jobs {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let (flags, operands) = options("jobs", &self.args, "lp")?;
+        if let Some(operand) = operands.first() {
+            return Err(InterpreterError::runtime(format!("jobs: {}: invalid argument", operand)));
+        }
+        match flags.last() {
+            Some('p') => for pgid in shell.jobs.pgids() {
+                shell.print_line(pgid);
+            },
+            flag => for line in shell.jobs.report(false, flag == Some(&'l')) {
+                shell.print_line(line);
+            }
+        }
+        Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }))
+    }
+}
+
+/// `fg [job]`, which resumes a job in the foreground and waits for it.
+struct Fg {
+    args: Vec<String>,
+}
+
+impl Fg {
+    fn new<S: ToString>(args: &[S]) -> Fg {
+        Fg{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Fg {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        if !shell.options.monitor {
+            return Err(InterpreterError::runtime("fg: no job control"));
+        }
+        let spec = match self.args.as_slice() {
+            [] => None,
+            [spec] => Some(spec.as_str()),
+            _ => return Err(InterpreterError::runtime("fg: too many arguments"))
+        };
+        let id = shell.jobs.resolve(spec).map_err(|err| InterpreterError::runtime(format!("fg: {}", err)))?;
+        let job = match shell.jobs.take(id) {
+            Some(job) => job,
+            None => return Err(InterpreterError::runtime(format!("fg: %{}: no such job", id)))
+        };
+        shell.print_line(job.command());
+        let (statuses, stopped) = shell.jobs.foreground(job, Some(id));
+        if let Some(line) = stopped {
+            shell.print_error(line);
+        }
+        Ok(Box::new(CompletedProcess{ result: shell.pipeline_status(&statuses) }))
+    }
+}
+
+/// `bg [job ...]`, which resumes stopped jobs in the background.
+struct Bg {
+    args: Vec<String>,
+}
+
+impl Bg {
+    fn new<S: ToString>(args: &[S]) -> Bg {
+        Bg{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Bg {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        if !shell.options.monitor {
+            return Err(InterpreterError::runtime("bg: no job control"));
+        }
+        let specs: Vec<Option<&str>> = match self.args.is_empty() {
+            true => vec![None],
+            false => self.args.iter().map(|spec| Some(spec.as_str())).collect()
+        };
+        let mut success = true;
+        for spec in specs {
+            match shell.jobs.resolve(spec).and_then(|id| shell.jobs.background(id)) {
+                Ok(line) => shell.print_line(line),
+                Err(err) => {
+                    shell.print_error(format!("bg: {}", err));
+                    success = false;
+                }
+            }
+        }
+        Ok(Box::new(CompletedProcess{ result: success.into() }))
+    }
+}
+
+/// `kill [-s sigspec | -sigspec] pid | %job ...` and `kill -l [status]`, which send a signal
+/// to processes or to every process of a job.
+struct Kill {
+    args: Vec<String>,
+}
+
+impl Kill {
+    fn new<S: ToString>(args: &[S]) -> Kill {
+        Kill{args: args.iter().map(S::to_string).collect()}
+    }
+
+    fn signal(spec: &str) -> InterpreterResult<libc::c_int> {
+        signals::number(spec).ok_or_else(|| InterpreterError::runtime(format!("kill: {}: invalid signal specification", spec)))
+    }
+
+    /// Resolves a target to the process to signal, or the negated process group of a job,
+    /// and whether it is a stopped job.
+    fn target(shell: &Interpreter, target: &str) -> Result<(libc::pid_t, bool), String> {
+        if target.starts_with('%') {
+            let job = shell.jobs.resolve(Some(target)).and_then(|id| shell.jobs.get(id).ok_or_else(|| format!("{}: no such job", target)))?;
+            return Ok((-job.pgid(), matches!(job.state(), jobs::State::Stopped(_))));
+        }
+        target.parse().map(|pid| (pid, false)).map_err(|_| format!("{}: arguments must be process or job IDs", target))
+    }
+
+    /// Lists the signal names, or names the signal given by number or by the status of a
+    /// command it killed.
+    fn list(shell: &Interpreter, operands: &[String]) -> InterpreterResult<ExitStatus> {
+        if operands.is_empty() {
+            let names: Vec<&str> = signals::list().iter().map(|(name, _)| *name).collect();
+            shell.print_line(names.join(" "));
+        }
+        for operand in operands {
+            let name = operand.parse::<libc::c_int>().ok()
+                .and_then(|number| signals::name(if number > 128 { number - 128 } else { number }));
+            match name {
+                Some(name) => shell.print_line(name),
+                None => return Err(InterpreterError::runtime(format!("kill: {}: invalid signal specification", operand)))
+            }
+        }
+        Ok(ExitStatus::SUCCESS)
+    }
+}
+
+impl Statement for Kill {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let (signal, targets) = match args.as_slice() {
+            [flag, operands@..] if flag == "-l" => {
+                return Ok(Box::new(CompletedProcess{ result: Self::list(shell, operands)? }));
+            }
+            [flag, spec, targets@..] if flag == "-s" => (Self::signal(spec)?, targets),
+            [flag, targets@..] if flag == "--" => (libc::SIGTERM, targets),
+            [flag, targets@..] if flag.len() > 1 && flag.starts_with('-') => (Self::signal(&flag[1..])?, targets),
+            targets => (libc::SIGTERM, targets)
+        };
+        if targets.is_empty() {
+            return Err(InterpreterError::runtime("kill: usage: kill [-s sigspec | -sigspec] pid | %job ... or kill -l [status]"));
+        }
+        let mut success = true;
+        for target in targets {
+            let (pid, stopped) = match Self::target(shell, target) {
+                Ok(target) => target,
+                Err(err) => {
+                    shell.print_error(format!("kill: {}", err));
+                    success = false;
+                    continue;
+                }
+            };
+            if let Err(err) = shell.spawner.kill(pid, signal) {
+                shell.print_error(format!("kill: ({}) - {}", target, physical::strerror(&err)));
+                success = false;
+            } else if stopped && (signal == libc::SIGTERM || signal == libc::SIGHUP) {
+                // A stopped job could not act on the signal until it was continued.
+                let _ = shell.spawner.kill(pid, libc::SIGCONT);
+            }
+        }
+        Ok(Box::new(CompletedProcess{ result: success.into() }))
+    }
+}
+
+/// `trap [-lp] [[action] condition ...]`, which sets the commands run when signals arrive
+/// or the shell raises one of its own conditions.
+struct Trap {
+    args: Vec<String>,
+}
+
+impl Trap {
+    fn new<S: ToString>(args: &[S]) -> Trap {
+        Trap{args: args.iter().map(S::to_string).collect()}
+    }
+
+    fn print(shell: &Interpreter, condition: Condition, action: &str) {
+        shell.print_line(format!("trap -- {} {}", substitution::quote(action), condition.name()));
+    }
+}
+
+impl Statement for Trap {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let (flags, operands) = options("trap", &args, "lp")?;
+        if flags.contains(&'l') {
+            shell.print_line(traps::list_signals());
+            return Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }));
+        }
+        if flags.contains(&'p') || operands.is_empty() {
+            let mut success = true;
+            if operands.is_empty() {
+                for (condition, action) in shell.traps.list() {
+                    Self::print(shell, condition, action);
+                }
+            }
+            for operand in operands {
+                match Condition::parse(operand) {
+                    Some(condition) => if let Some(action) = shell.traps.get(condition) {
+                        Self::print(shell, condition, action);
+                    },
+                    None => {
+                        shell.print_error(format!("trap: {}: invalid signal specification", operand));
+                        success = false;
+                    }
+                }
+            }
+            return Ok(Box::new(CompletedProcess{ result: success.into() }));
+        }
+        // A lone condition, or `-` in place of the action, resets the conditions given.
+        let (action, conditions) = match operands {
+            [condition] => (None, std::slice::from_ref(condition)),
+            [action, conditions@..] if action == "-" => (None, conditions),
+            [action, conditions@..] => (Some(action), conditions),
+            [] => (None, operands)
+        };
+        let mut success = true;
+        for spec in conditions {
+            let result = match Condition::parse(spec) {
+                Some(condition) => shell.traps.set(condition, action.cloned()).map_err(|err| format!("trap: {}: {}", spec, err)),
+                None => Err(format!("trap: {}: invalid signal specification", spec))
+            };
+            if let Err(err) = result {
+                shell.print_error(err);
+                success = false;
+            }
+        }
+        Ok(Box::new(CompletedProcess{ result: success.into() }))
+    }
+}
+
+/// `exit [n]`, which abandons the rest of the input with status `n`, or the status of the
+/// last pipeline.
+struct Exit {
+    args: Vec<String>,
+}
+
+impl Exit {
+    fn new<S: ToString>(args: &[S]) -> Exit {
+        Exit{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Exit {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let status = status_operand("exit", &self.args, shell)?;
+        shell.exit = Some(status);
+        Ok(Box::new(CompletedProcess{ result: status }))
+    }
+}
+
+/// `alias [-p] [name[=value] ...]`, which defines aliases or prints their definitions.
+struct Alias {
+    args: Vec<String>,
+}
+
+impl Alias {
+    fn new<S: ToString>(args: &[S]) -> Alias {
+        Alias{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Alias {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let (flags, operands) = options("alias", &args, "p")?;
+        if operands.is_empty() || !flags.is_empty() {
+            for (name, value) in shell.aliases.list() {
+                shell.print_line(aliases::format(name, value));
+            }
+        }
+        let mut result = ExitStatus::SUCCESS;
+        for operand in operands {
+            let defined = match operand.split_once('=') {
+                Some((name, value)) => shell.aliases.set(name, value),
+                None => match shell.aliases.get(operand) {
+                    Some(value) => {
+                        shell.print_line(aliases::format(operand, value));
+                        true
+                    }
+                    None => {
+                        shell.print_error(format!("alias: {}: not found", operand));
+                        result = ExitStatus::FAILURE;
+                        continue;
+                    }
+                }
+            };
+            if !defined {
+                shell.print_error(format!("alias: `{}': invalid alias name", operand));
+                result = ExitStatus::FAILURE;
+            }
+        }
+        Ok(Box::new(CompletedProcess{ result }))
+    }
+}
+
+/// `unalias -a` or `unalias name ...`, which removes all or the named aliases.
+struct Unalias {
+    args: Vec<String>,
+}
+
+impl Unalias {
+    fn new<S: ToString>(args: &[S]) -> Unalias {
+        Unalias{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Unalias {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let (flags, operands) = options("unalias", &args, "a")?;
+        if !flags.is_empty() {
+            shell.aliases.clear();
+        } else if operands.is_empty() {
+            return Err(InterpreterError::runtime("unalias: usage: unalias [-a] name [name ...]"));
+        }
+        let mut result = ExitStatus::SUCCESS;
+        for operand in operands {
+            if !shell.aliases.remove(operand) && flags.is_empty() {
+                shell.print_error(format!("unalias: {}: not found", operand));
+                result = ExitStatus::FAILURE;
+            }
+        }
+        Ok(Box::new(CompletedProcess{ result }))
+    }
+}
+
+/// `exec [command [args]]` with redirections such as `3>file`, `4<file`, `2>&1` or `3>&-`.
+/// Without a command the redirections open and close the shell's own descriptors for good,
+/// and later commands inherit them. With one, the shell is replaced by the command.
+struct Exec {
+    words: Vec<String>,
+    redirects: Vec<FdRedirect>
+}
+
+impl Exec {
+    fn new<S: AsRef<str>>(tokens: &[S], spans: &[Span]) -> InterpreterResult<Exec> {
+        let (words, _, redirects) = FdRedirect::parse(tokens, spans)?;
+        Ok(Exec{ words, redirects })
+    }
+}
+
+impl Statement for Exec {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut argv = vec![];
+        for word in &self.words {
+            argv.extend(fields(word, &mut shell.environment, context)?);
+        }
+        for redirect in &self.redirects {
+            let file = redirect.file(shell)?;
+            shell.set_fd(redirect.fd, file)?;
+        }
+        let Some(program) = argv.first().cloned() else {
+            return Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }));
+        };
+        let request = Command::request(shell, argv, Environment::new())?;
+        let mut process = match shell.spawner.exec(request) {
+            Ok(process) => process,
+            Err(err) => {
+                let err = Command::spawn_error(program, err);
+                // Only an interactive shell survives a command it could not become.
+                if !shell.interactive() {
+                    shell.exit = Some(ExitStatus::new(err.exit_code()));
+                }
+                return Err(err);
+            }
+        };
+        // Only a spawner that runs no real processes returns: the command stands in for the
+        // rest of the shell.
+        let status = process.wait();
+        shell.exit = Some(status);
+        Ok(Box::new(CompletedProcess{ result: status }))
+    }
+}
+
+/// Moves a descriptor the shell opens to the number `fd` if that is free, so that nothing
+/// else in the process can take the number children expect it at, or otherwise above the
+/// numbers redirections may set.
+fn place(file: File, fd: RawFd) -> io::Result<File> {
+    if file.as_raw_fd() == fd {
+        return Ok(file);
+    }
+    for lowest in [fd, 10] {
+        let placed = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, lowest) };
+        if placed == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just duplicated and nothing else owns it.
+        let placed = unsafe { File::from_raw_fd(placed) };
+        if placed.as_raw_fd() == fd || lowest == 10 {
+            return Ok(placed);
+        }
+    }
+    unreachable!()
+}
+
+/// Expands the optional status given to `exit` or `return`, which defaults to the status of
+/// the last pipeline.
+fn status_operand(name: &str, args: &[String], shell: &mut Interpreter) -> InterpreterResult<ExitStatus> {
+    let context = shell.context();
+    let mut expanded = vec![];
+    for arg in args {
+        expanded.extend(fields(arg, &mut shell.environment, context)?);
+    }
+    match expanded.as_slice() {
+        [] => Ok(shell.last_status),
+        [code] => match code.parse::<i64>() {
+            Ok(code) => Ok(ExitStatus::new((code & 0xff) as i32)),
+            Err(_) => {
+                shell.print_error(format!("{}: {}: numeric argument required", name, code));
+                Ok(ExitStatus::new(2))
+            }
+        },
+        _ => Err(InterpreterError::runtime(format!("{}: too many arguments", name)))
+    }
+}
+
+/// `return [n]`, which stops running the file being sourced.
+struct Return {
+    args: Vec<String>,
+}
+
+impl Return {
+    fn new<S: ToString>(args: &[S]) -> Return {
+        Return{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Return {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        if !shell.scripts.iter().any(|script| script.name.is_some()) {
+            return Err(InterpreterError::runtime("return: can only `return' from a sourced file"));
+        }
+        let status = status_operand("return", &self.args, shell)?;
+        shell.returned = Some(status);
+        Ok(Box::new(CompletedProcess{ result: status }))
+    }
+}
+
+/// `source file [args]` and `. file [args]`, which run the commands in a file in the
+/// current shell. Arguments replace the positional parameters until the file finishes.
+struct Source {
+    args: Vec<String>,
+}
+
+impl Source {
+    fn new<S: ToString>(args: &[S]) -> Source {
+        Source{args: args.iter().map(S::to_string).collect()}
+    }
+
+    /// Searches `PATH` for a file named without a slash, falling back to the working
+    /// directory.
+    fn search(file: &str, env: &Environment, cwd: &Path) -> PathBuf {
+        if let (false, Some(path)) = (file.contains('/'), env.get("PATH")) {
+            for entry in path.split(':') {
+                let candidate = Path::new(if entry.is_empty() { "." } else { entry }).join(file);
+                if physical::resolve(cwd, &candidate).is_file() {
+                    return candidate;
+                }
+            }
+        }
+        PathBuf::from(file)
+    }
+}
+
+impl Statement for Source {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let env = &mut shell.environment;
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, env, context)?);
+        }
+        let Some((file, params)) = args.split_first() else {
+            return Err(InterpreterError::runtime("source: filename argument required"));
+        };
+        let path = Self::search(file, env, &shell.cwd);
+        let saved = (!params.is_empty()).then(|| {
+            let saved = substitution::positional(env);
+            substitution::set_positional(params, env);
+            saved
+        });
+        let result = shell.source(path);
+        if let Some(saved) = saved {
+            substitution::set_positional(&saved, &mut shell.environment);
+        }
+        let status = result.map_err(|err| InterpreterError::runtime(format!("source: {}: {}", file, physical::strerror(&err))))?;
+        Ok(Box::new(CompletedProcess{ result: status }))
+    }
+}
+
+struct Pwd {
+    args: Vec<String>,
+}
+
+impl Pwd {
+    fn new<S: ToString>(args: &[S]) -> Pwd {
+        Pwd{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Pwd {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let env = &shell.environment;
+        let (flags, operands) = options("pwd", &self.args, "LP")?;
+        if !operands.is_empty() {
+            return Err(InterpreterError::runtime("pwd: too many arguments"));
+        }
+        match env.get("PWD") {
+            Some(pwd) if flags.last() != Some(&'P') && Path::new(pwd).is_absolute() && physical::same_file(pwd, &shell.cwd) => shell.print_line(pwd),
+            _ => shell.print_line(std::fs::canonicalize(&shell.cwd)?.display())
+        }
+        Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }))
+    }
+}
+
+/// Parses a `+n` or `-n` directory stack index, counting from the left or right respectively,
+/// into an index into a stack of `len` entries.
+fn stack_index(name: &str, arg: &str, len: usize) -> Option<InterpreterResult<usize>> {
+    let n: usize = arg.get(1..)?.parse().ok()?;
+    let index = match arg.chars().next()? {
+        '+' => Some(n),
+        '-' => (len - 1).checked_sub(n),
+        _ => return None
+    };
+    Some(index.filter(|&index| index < len)
+        .ok_or_else(|| InterpreterError::runtime(format!("{}: {}: directory stack index out of range", name, arg))))
+}
+
+/// Formats the directory stack the way `dirs` prints it. Unless `long`, directories under
+/// `HOME` are abbreviated with a tilde.
+fn list_dirs(dirs: &[String], env: &Environment, long: bool, vertical: bool, numbered: bool) -> String {
+    let home = env.get("HOME").filter(|home| !home.is_empty() && !long);
+    let dirs = dirs.iter().map(|dir| match home {
+        Some(home) if dir == home => "~".to_string(),
+        Some(home) if dir.starts_with(home.as_str()) && dir[home.len()..].starts_with('/') => format!("~{}", &dir[home.len()..]),
+        _ => dir.clone()
+    });
+    if numbered {
+        dirs.enumerate().map(|(index, dir)| format!("{:2}  {}", index, dir)).collect::<Vec<String>>().join("\n")
+    } else {
+        dirs.collect::<Vec<String>>().join(if vertical { "\n" } else { " " })
+    }
+}
+
+struct Pushd {
+    args: Vec<String>,
+}
+
+impl Pushd {
+    fn new<S: ToString>(args: &[S]) -> Pushd {
+        Pushd{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Pushd {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let (no_change, operands) = match args.split_first() {
+            Some((first, rest)) if first == "-n" => (true, rest),
+            _ => (false, args.as_slice())
+        };
+        let mut dirs = shell.dirs();
+        match operands {
+            [] => {
+                if dirs.len() < 2 {
+                    return Err(InterpreterError::runtime("pushd: no other directory"));
+                }
+                if !no_change {
+                    dirs.swap(0, 1);
+                }
+            }
+            [arg] => match stack_index("pushd", arg, dirs.len()) {
+                Some(index) => match index? {
+                    0 => {}
+                    // Without a directory change the current directory stays on top.
+                    index if no_change => dirs[1..].rotate_left(index - 1),
+                    index => dirs.rotate_left(index)
+                },
+                None if no_change => dirs.insert(1, arg.to_string()),
+                None => {
+                    let (target, _) = CD::search(arg, &shell.environment, &shell.cwd);
+                    if !CD::change(shell, target, false)?.wait().success() {
+                        return Ok(Box::new(CompletedProcess{ result: ExitStatus::FAILURE }));
+                    }
+                    dirs.insert(0, shell.pwd());
+                }
+            },
+            _ => return Err(InterpreterError::runtime("pushd: too many arguments"))
+        }
+        if dirs[0] != shell.pwd() && !CD::change(shell, PathBuf::from(&dirs[0]), false)?.wait().success() {
+            return Ok(Box::new(CompletedProcess{ result: ExitStatus::FAILURE }));
+        }
+        shell.dirstack = dirs.split_off(1);
+        shell.sync_dirstack();
+        shell.print_line(list_dirs(&shell.dirs(), &shell.environment, false, false, false));
+        Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }))
+    }
+}
+
+struct Popd {
+    args: Vec<String>,
+}
+
+impl Popd {
+    fn new<S: ToString>(args: &[S]) -> Popd {
+        Popd{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Popd {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let (no_change, operands) = match args.split_first() {
+            Some((first, rest)) if first == "-n" => (true, rest),
+            _ => (false, args.as_slice())
+        };
+        let mut dirs = shell.dirs();
+        if dirs.len() < 2 {
+            return Err(InterpreterError::runtime("popd: directory stack empty"));
+        }
+        let index = match operands {
+            [] => 0,
+            [arg] => match stack_index("popd", arg, dirs.len()) {
+                Some(index) => index?,
+                None => return Err(InterpreterError::runtime(format!("popd: {}: invalid argument", arg)))
+            },
+            _ => return Err(InterpreterError::runtime("popd: too many arguments"))
+        };
+        match index {
+            0 if no_change => { dirs.remove(1); }
+            0 => {
+                dirs.remove(0);
+                if !CD::change(shell, PathBuf::from(&dirs[0]), false)?.wait().success() {
+                    return Ok(Box::new(CompletedProcess{ result: ExitStatus::FAILURE }));
+                }
+            }
+            index => { dirs.remove(index); }
+        }
+        shell.dirstack = dirs.split_off(1);
+        shell.sync_dirstack();
+        shell.print_line(list_dirs(&shell.dirs(), &shell.environment, false, false, false));
+        Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }))
+    }
+}
+
+struct Dirs {
+    args: Vec<String>,
+}
+
+impl Dirs {
+    fn new<S: ToString>(args: &[S]) -> Dirs {
+        Dirs{args: args.iter().map(S::to_string).collect()}
+    }
+}
+
+impl Statement for Dirs {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>> {
+        let context = shell.context();
+        let mut args = vec![];
+        for arg in &self.args {
+            args.extend(fields(arg, &mut shell.environment, context)?);
+        }
+        let mut dirs = shell.dirs();
+        let (mut clear, mut long, mut vertical, mut numbered) = (false, false, false, false);
+        for arg in &args {
+            if let Some(index) = stack_index("dirs", arg, dirs.len()) {
+                dirs = vec![dirs.swap_remove(index?)];
+                continue;
+            }
+            let (flags, operands) = options("dirs", std::slice::from_ref(arg), "clpv")?;
+            if !operands.is_empty() {
+                return Err(InterpreterError::runtime(format!("dirs: {}: invalid argument", arg)));
+            }
+            for flag in flags {
+                match flag {
+                    'c' => clear = true,
+                    'l' => long = true,
+                    'p' => vertical = true,
+                    _ => numbered = true
+                }
+            }
+        }
+        if clear {
+            shell.dirstack.clear();
+            shell.sync_dirstack();
+        } else {
+            shell.print_line(list_dirs(&dirs, &shell.environment, long, vertical, numbered));
+        }
+        Ok(Box::new(CompletedProcess{ result: ExitStatus::SUCCESS }))
+    }
+}
+
+/// A compiled piece of shell input. Statements read and write the interpreter's streams,
+/// which a pipeline points at the pipes between its stages.
+pub(crate) trait Statement {
+    fn eval(&mut self, shell: &mut Interpreter) -> InterpreterResult<Box<dyn Process>>;
+    /// Whether evaluating the statement runs code in the shell itself, rather than only
+    /// starting an external command. A pipeline forks such stages so that they run
+    /// concurrently with the stages reading their output.
+    fn in_process(&self, _shell: &Interpreter) -> bool {
+        true
+    }
+}
+
+
+/// Entry points for the fuzz targets in `fuzz/`, which exercise the stages of the
+/// interpreter on their own. Not part of the supported API.
+#[doc(hidden)]
+pub mod fuzzing {
+    use super::*;
+
+    /// Tokenizes and compiles `input` without evaluating it.
+    pub fn compile(input: &str) -> InterpreterResult<()> {
+        let arena = Bump::new();
+        let mut lexer = lexer::tokenize(input)?.into_iter().peekable();
+        Interpreter::compile(&arena, &mut lexer).map(|_| ())
+    }
+
+    /// Expands `word` both as a whole and into fields.
+    pub fn expand(word: &str, env: &mut Environment) -> InterpreterResult<Vec<String>> {
+        let context = substitution::Context::default();
+        substitution(word, env, context)?;
+        fields(word, env, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The messages reported on `stderr`, without the `rsh:` prefix or the lines of input
+    /// beneath them.
+    fn messages(stderr: &[u8]) -> String {
+        String::from_utf8_lossy(stderr).lines().filter(|line| !line.starts_with("  "))
+            .map(|line| format!("{}\n", line.strip_prefix("rsh: ").unwrap_or(line))).collect()
+    }
+
+    #[test]
+    fn test_arithmetic_command() {
+        let mut i = Interpreter::new();
+        assert!(i.interpret("(( 2 > 1 ))").unwrap());
+        assert!(!i.interpret("(( 1 - 1 ))").unwrap());
+        assert!(i.interpret("x=4; (( x *= 2, x == 8 ))").unwrap());
+        assert_eq!(i.environment.get("x").unwrap(), "8");
+    }
+
+    #[test]
+    fn test_arithmetic_division_by_zero() {
+        let mut i = Interpreter::new();
+        assert_eq!(i.interpret("(( 1 / 0 ))").unwrap_err().to_string(), "division by 0");
+    }
+
+    #[test]
+    fn test_arithmetic_expansion() {
+        let mut i = Interpreter::new();
+        assert!(i.interpret("x=$(( 1 + 2 * 3 )); (( x == 7 ))").unwrap());
+    }
+
+    #[test]
+    fn test_test_builtin() {
+        let mut i = Interpreter::new();
+        assert!(i.interpret("[ -d src ] && test -f Cargo.toml").unwrap());
+        assert!(!i.interpret("x=5; [ $x -lt 3 ]").unwrap());
+        assert!(i.interpret("[ ! -e nope -a 1 = 1 ]").unwrap());
+        // Usage errors exit with 2, which tells them apart from a false expression.
+        assert_eq!(i.run("[ 1 -eq 1").unwrap().code(), 2);
+        assert_eq!(i.run("test 1 -eq a").unwrap().code(), 2);
+        assert_eq!(i.run("test 1 -eq 2").unwrap().code(), 1);
+        assert!(i.interpret("b=']'; [ x = x $b").unwrap());
+    }
+
+    #[test]
+    fn test_conditional_command() {
+        let mut i = Interpreter::new();
+        assert!(i.interpret("v='rsh 2.3'; [[ $v == rsh* && $v =~ ([0-9])\\.([0-9]) ]]").unwrap());
+        assert_eq!(i.environment.get("BASH_REMATCH[2]").unwrap(), "3");
+        assert!(!i.interpret("[[ -z $v || ( $v != *rsh* ) ]]").unwrap());
+        assert!(i.interpret("[[ a < b ]] || [[ b ]]").unwrap());
+        assert!(i.interpret("[[ a == b").is_err());
+    }
+
+    #[test]
+    fn test_word_splitting() {
+        let mut i = Interpreter::new();
+        assert!(i.interpret("FILES=\"Cargo.toml src\"; ls $FILES > /dev/null").unwrap());
+        assert!(i.interpret("[ \"$FILES\" != Cargo.toml ]").unwrap());
+        assert!(i.interpret("EMPTY=; $EMPTY [ -d src ]").unwrap());
+        assert!(i.interpret("[ \"$EMPTY\" = '' ]").unwrap());
+    }
+
+    #[test]
+    fn test_tilde_expansion() {
+        let mut i = Interpreter::new();
+        i.environment.insert("HOME".to_string(), "/home/rsh".to_string());
+        assert!(i.interpret("P=~/bin:~/.cargo/bin; [ $P = /home/rsh/bin:/home/rsh/.cargo/bin ]").unwrap());
+        assert!(i.interpret("[ '~' != ~ ] && [ ~no_such_user_rsh = '~no_such_user_rsh' ]").unwrap());
+        let root = physical::user_home("root").expect("no home directory for root");
+        i.environment.insert("ROOT".to_string(), root.join("x").to_string_lossy().into_owned());
+        assert!(i.interpret("[ ~root/x = \"$ROOT\" ]").unwrap());
+    }
+
+    #[test]
+    fn test_cd_errors() {
+        let mut i = Interpreter::new();
+        i.environment.remove("OLDPWD");
+        assert_eq!(i.interpret("cd -").unwrap_err().to_string(), "cd: OLDPWD not set");
+        assert_eq!(i.interpret("cd a b").unwrap_err().to_string(), "cd: too many arguments");
+        assert_eq!(i.interpret("cd -X").unwrap_err().to_string(), "cd: -X: invalid option");
+        assert_eq!(i.interpret("HOME=; cd").unwrap_err().to_string(), "cd: HOME not set");
+    }
+
+    #[test]
+    fn test_cd_updates_pwd() {
+        let mut i = Interpreter::new();
+        let cwd = i.environment.get("PWD").unwrap().clone();
+        assert!(i.interpret("cd $PWD/src/../. && cd -L -- \"$PWD\"").unwrap());
+        assert_eq!(i.environment.get("PWD").unwrap(), &cwd);
+        assert_eq!(i.environment.get("OLDPWD").unwrap(), &cwd);
+    }
+
+    #[test]
+    fn test_builtin_assignments() {
+        let mut i = Interpreter::builder().env(vec![("HOME", "/")]).build().unwrap();
+        // Assignments before a regular builtin last only while it runs.
+        let output = i.capture("HOME=/tmp cd; pwd; echo $HOME; A=1 B=$A dirs >/dev/null; echo \"$A\"").unwrap();
+        assert_eq!(output.stdout, b"/tmp\n/\n\n");
+        // Those before a special builtin stay set.
+        assert_eq!(i.capture("A=2 export B=3; echo $A $B").unwrap().stdout, b"2 3\n");
+    }
+
+    #[test]
+    fn test_export_listing() {
+        let mut i = Interpreter::builder().env(vec![("HOME", "/")]).build().unwrap();
+        let output = i.capture("export QUOTE=\"it's\" ARR[1]=x; export | grep -e QUOTE -e ARR; export -p | grep HOME").unwrap();
+        assert_eq!(output.stdout, b"export QUOTE='it'\\''s'\nexport HOME='/'\n");
+    }
+
+    #[test]
+    fn test_directory_stack() {
+        let mut i = Interpreter::new();
+        let cwd = i.environment["PWD"].clone();
+        assert!(i.interpret("pushd -n /tmp && pushd -n $PWD && pushd +1").unwrap());
+        assert_eq!(substitution::array("DIRSTACK", &i.environment), vec![cwd.as_str(), "/tmp", cwd.as_str()]);
+        assert!(i.interpret("popd -n && popd +0").unwrap());
+        assert_eq!(i.environment["PWD"], cwd);
+        assert_eq!(i.dirstack, Vec::<String>::new());
+        assert_eq!(i.interpret("popd").unwrap_err().to_string(), "popd: directory stack empty");
+        assert_eq!(i.interpret("pushd -n /tmp; dirs +3").unwrap_err().to_string(), "dirs: +3: directory stack index out of range");
+        assert!(i.interpret("dirs -c && [ ${DIRSTACK[@]} = $PWD ]").unwrap());
+    }
+
+    #[test]
+    fn test_builtin_redirects() {
+        let dir = std::env::temp_dir().join(format!("rsh-builtin-redirects-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).build().unwrap();
+        let output = i.capture("pwd > file; cd . > out; dirs -v > stack 2>&1; cat file stack out").unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{0}\n 0  {0}\n", dir.display()));
+        let output = i.capture("cd missing 2> err; wc -l <err").unwrap();
+        assert_eq!((output.stdout, output.stderr), (b"1\n".to_vec(), b"".to_vec()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_per_interpreter_cwd() {
+        let mut a = Interpreter::new();
+        let mut b = Interpreter::new();
+        assert!(a.interpret("cd src && [ -f main.rs ] && ls main.rs > /dev/null").unwrap());
+        assert!(b.interpret("[ -d src ] && [ ! -e main.rs ]").unwrap());
+        assert!(a.interpret("cd .. && [ -d src ] && cd /tmp && echo cwd > rsh_cwd_$$").unwrap());
+        let file = Path::new("/tmp").join(format!("rsh_cwd_{}", std::process::id()));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "cwd\n");
+        std::fs::remove_file(file).unwrap();
+        assert!(std::env::current_dir().unwrap().join("src").is_dir());
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut i = Interpreter::builder()
+            .env(vec![("PATH", std::env::var("PATH").unwrap_or_default())])
+            .var("GREETING", "hello world")
+            .cwd("src")
+            .name("deploy")
+            .args(vec!["a", "b c"])
+            .build()
+            .unwrap();
+        assert_eq!(i.cwd(), std::env::current_dir().unwrap().join("src"));
+        assert_eq!(i.get_var("PWD"), i.cwd().to_str());
+        assert_eq!(i.get_var("HOME"), None);
+        assert!(i.interpret("[ -f main.rs ] && [ \"$0:$#:$2\" = 'deploy:2:b c' ] && [ \"$GREETING\" = 'hello world' ]").unwrap());
+        i.set_var("GREETING", "bye");
+        assert_eq!(i.run("sh -c 'exit $(( ${#GREETING} + 4 ))'").unwrap().code(), 7);
+        assert!(Interpreter::builder().cwd("Cargo.toml").build().is_err());
+    }
+
+    #[test]
+    fn test_registered_builtins() {
+        let file = std::env::temp_dir().join(format!("rsh_builtin_{}", std::process::id()));
+        let mut i = Interpreter::builder()
+            .builtin("upper", |_: &[String], _: &mut Environment, stdio: &mut BuiltinStdio| {
+                let mut input = String::new();
+                stdio.stdin.read_to_string(&mut input).unwrap();
+                stdio.stdout.write_all(input.to_uppercase().as_bytes()).unwrap();
+                ExitStatus::SUCCESS
+            })
+            .build()
+            .unwrap();
+        i.register_builtin("greet", |args: &[String], env: &mut Environment, stdio: &mut BuiltinStdio| {
+            writeln!(stdio.stdout, "hello {}", env.get("NAME").map_or("nobody", String::as_str)).unwrap();
+            env.insert("GREETED".to_string(), args[1..].join(" "));
+            ExitStatus::new(args.len() as i32)
+        });
+        assert_eq!(i.run("NAME=rsh greet a b | upper | grep -q 'HELLO RSH'").unwrap(), ExitStatus::SUCCESS);
+        assert_eq!(i.get_var("NAME"), None);
+        // Builtins piped into another stage run in a subshell.
+        assert_eq!(i.get_var("GREETED"), None);
+        assert_eq!(i.run(format!("greet x y > {}", file.display())).unwrap().code(), 3);
+        assert_eq!(i.get_var("GREETED"), Some("x y"));
+        assert!(i.interpret(format!("cat {} | upper >> {}", file.display(), file.display())).unwrap());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "hello nobody\nHELLO NOBODY\n");
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut i = Interpreter::builder().cwd("src").build().unwrap();
+        let output = i.capture("pwd; sh -c 'echo oops >&2; exit 4'").unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{}\n", i.cwd().display()));
+        assert_eq!(output.stderr, b"oops\n");
+        assert_eq!(output.status.code(), 4);
+        let output = i.capture("[ 1 -gt ]").unwrap();
+        assert_eq!(output.stdout, b"");
+        assert_eq!(output.stderr, b"test: argument expected\n");
+        assert!(!output.status.success());
+    }
+
+    #[test]
+    fn test_failure_is_a_status() {
+        let mock = MockSpawner::new();
+        mock.script("true", Scripted::default());
+        mock.script("false", Scripted{ status: ExitStatus::FAILURE, ..Scripted::default() });
+        mock.script("echo", Scripted{ stdout: b"hi\n".to_vec(), ..Scripted::default() });
+        let mut i = Interpreter::builder().spawner(mock).build().unwrap();
+        let output = i.capture("false && echo").unwrap();
+        assert_eq!((output.stdout, output.stderr, output.status), (vec![], vec![], ExitStatus::FAILURE));
+        let output = i.capture("true && false || echo").unwrap();
+        assert_eq!((output.stdout, output.stderr, output.status), (b"hi\n".to_vec(), vec![], ExitStatus::SUCCESS));
+        let output = i.capture("cd /no/such/dir || echo").unwrap();
+        assert_eq!(output.stdout, b"hi\n");
+        assert_eq!(messages(&output.stderr), "cd: /no/such/dir: No such file or directory\n");
+        let output = i.capture("cd /no/such/dir && echo; pushd /no/such/dir").unwrap();
+        assert_eq!((output.stdout.len(), output.status), (0, ExitStatus::FAILURE));
+        assert_eq!(output.stderr.split(|&b| b == b'\n').filter(|line| !line.is_empty()).count(), 2);
+        let output = i.capture("missing && echo; missing || echo").unwrap();
+        assert_eq!(output.stdout, b"hi\n");
+        assert_eq!(output.stderr, b"rsh: missing: command not found\n  missing && echo; missing || echo\n  ^^^^^^^\n\
+                                    rsh: missing: command not found\n  missing && echo; missing || echo\n                   ^^^^^^^\n");
+        assert_eq!(i.run("true; missing || false").unwrap(), ExitStatus::FAILURE);
+    }
+
+    #[test]
+    fn test_status_parameter() {
+        let mut i = Interpreter::new();
+        let output = i.capture("false && echo hi; echo $?; ! true; echo $? \"$?\"; missing; echo ${?}; true; echo $?").unwrap();
+        assert_eq!(output.stdout, b"1\n1 1\n127\n0\n");
+    }
+
+    #[test]
+    fn test_error_statuses() {
+        let dir = std::env::temp_dir().join(format!("rsh-error-statuses-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib"), "missing\nreturn\n").unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).build().unwrap();
+        assert_eq!(i.capture("missing; echo ${PIPESTATUS[@]}").unwrap().stdout, b"127\n");
+        assert_eq!(i.source("lib").unwrap().code(), 127);
+        i.capture("missing; exit").unwrap();
+        assert_eq!(i.exited(), Some(ExitStatus::new(127)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_long_lists() {
+        let mut i = Interpreter::new();
+        assert!(i.interpret(format!("{}A=2\n", "A=1\n".repeat(50_000))).unwrap());
+        assert!(i.interpret(format!("{}B=2", "B=1 && ! C=1 || ".repeat(50_000))).unwrap());
+        assert_eq!((i.get_var("A"), i.get_var("B")), (Some("2"), Some("2")));
+    }
+
+    #[test]
+    fn test_pipeline_prefixes() {
+        let mock = MockSpawner::new();
+        mock.script("true", Scripted::default());
+        mock.script("false", Scripted{ status: ExitStatus::FAILURE, ..Scripted::default() });
+        let mut i = Interpreter::builder().spawner(mock).build().unwrap();
+        assert_eq!(i.run("! false").unwrap(), ExitStatus::SUCCESS);
+        assert_eq!(i.run("! true").unwrap(), ExitStatus::FAILURE);
+        assert_eq!(i.run("! ! true && ! [[ a == b ]] && ! (( 0 ))").unwrap(), ExitStatus::SUCCESS);
+        assert_eq!(i.run("! missing").unwrap(), ExitStatus::SUCCESS);
+        assert_eq!(i.run("!").unwrap(), ExitStatus::FAILURE);
+        assert_eq!(i.run("echo ! time").unwrap_err().to_string(), "echo: command not found");
+        let output = i.capture("time -p ! true; time").unwrap();
+        assert_eq!(output.status, ExitStatus::SUCCESS);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let lines: Vec<&str> = stderr.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert!(lines[0].starts_with("real ") && lines[1].starts_with("user ") && lines[2].starts_with("sys "));
+        assert_eq!((lines[3], &lines[4][..5]), ("", "real\t"));
+    }
+
+    #[test]
+    fn test_format_times() {
+        let (real, user, sys) = (Duration::from_millis(61_234), Duration::from_millis(5), Duration::ZERO);
+        assert_eq!(format_times(real, user, sys, false), "\nreal\t1m1.234s\nuser\t0m0.005s\nsys\t0m0.000s");
+        assert_eq!(format_times(real, user, sys, true), "real 61.23\nuser 0.00\nsys 0.00");
+    }
+
+    #[test]
+    fn test_pipelines() {
+        let mut i = Interpreter::new();
+        let output = i.capture("sh -c 'echo a; exit 3' | tr a b | sh -c 'cat; exit 0'").unwrap();
+        assert_eq!((output.stdout, output.status), (b"b\n".to_vec(), ExitStatus::SUCCESS));
+        assert_eq!(substitution::array("PIPESTATUS", &i.environment), vec!["3", "0", "0"]);
+        assert_eq!(i.run("set -o pipefail; sh -c 'exit 3' | sh -c 'exit 4' | true").unwrap().code(), 4);
+        assert_eq!(i.run("sh -c 'exit 3' | missing").unwrap().code(), 127);
+        assert_eq!(substitution::array("PIPESTATUS", &i.environment), vec!["3", "127"]);
+        assert_eq!(i.run("set +o pipefail; false | true").unwrap(), ExitStatus::SUCCESS);
+        assert_eq!(i.run("true").unwrap(), ExitStatus::SUCCESS);
+        assert_eq!(substitution::array("PIPESTATUS", &i.environment), vec!["0"]);
+    }
+
+    #[test]
+    fn test_in_process_stages() {
+        let mut i = Interpreter::builder().cwd("src").build().unwrap();
+        i.register_builtin("many", |_: &[String], _: &mut Environment, stdio: &mut BuiltinStdio| {
+            ExitStatus::from((0..100_000).all(|n| writeln!(stdio.stdout, "{}", n).is_ok()))
+        });
+        let output = i.capture("pwd | tr a-z A-Z; set -o | grep pipefail; many | wc -l; many | head -n 1").unwrap();
+        let expected = format!("{}\npipefail       \toff\n100000\n0\n", i.cwd().display().to_string().to_uppercase());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+        assert_eq!(substitution::array("PIPESTATUS", &i.environment), vec!["1", "0"]);
+        assert!(i.run("cd .. | cat; set -o pipefail | cat").unwrap().success());
+        assert!(i.cwd().ends_with("src") && !i.options.pipefail);
+        assert!(i.run("echo x | cd ..").unwrap().success() && !i.cwd().ends_with("src"));
+        let output = i.capture("true | many | sh -c 'exit 2' | cat").unwrap();
+        assert_eq!(substitution::array("PIPESTATUS", &i.environment), vec!["0", "1", "2", "0"]);
+        assert_eq!(output.stdout, b"");
+        // Whether a stage is a builtin is decided once its name has been expanded.
+        let output = i.capture("\"many\" | head -n 1; B=many; $B | wc -l").unwrap();
+        assert_eq!(output.stdout, b"0\n100000\n");
+    }
+
+    #[test]
+    fn test_in_process_stages_on_threads() {
+        // Interpreters on other threads take the locks a forked stage needs while it is forked.
+        let threads: Vec<_> = (0..4).map(|_| std::thread::spawn(|| {
+            let mut i = Interpreter::new();
+            (0..20).all(|_| i.capture("trap true USR1; echo x | cat; trap - USR1").unwrap().stdout == b"x\n")
+        })).collect();
+        assert!(threads.into_iter().all(|thread| thread.join().unwrap()));
+    }
+
+    #[test]
+    fn test_job_control() {
+        let mut i = Interpreter::builder().option("monitor", true).build().unwrap();
+        // Every job must have finished for the captured output to be closed.
+        let output = i.capture("sh -c 'kill -STOP $$; exit 4'; echo $?; sh -c 'kill -STOP $$; exit 5'; jobs; \
+                                bg %-; kill %2; fg %2; echo $?; fg; echo $?; jobs; fg; kill %3; kill -l 143 2").unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "147\n\
+                                                             [1]-  Stopped                 sh -c 'kill -STOP $$; exit 4'\n\
+                                                             [2]+  Stopped                 sh -c 'kill -STOP $$; exit 5'\n\
+                                                             [1]+ sh -c 'kill -STOP $$; exit 4' &\n\
+                                                             sh -c 'kill -STOP $$; exit 5'\n143\n\
+                                                             sh -c 'kill -STOP $$; exit 4'\n4\n\
+                                                             TERM\nINT\n");
+        assert_eq!(messages(&output.stderr), "[1]+  Stopped                 sh -c 'kill -STOP $$; exit 4'\n\
+                                                             [2]+  Stopped                 sh -c 'kill -STOP $$; exit 5'\n\
+                                                             fg: current: no such job\n\
+                                                             kill: %3: no such job\n");
+        assert_eq!(Interpreter::new().run("fg").unwrap_err().to_string(), "fg: no job control");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut i = Interpreter::new();
+        for input in ["sh -c 'kill -INT $$'; echo after", "sh -c 'kill -INT $$' && echo a || echo b", "sh -c 'kill -INT $$' | cat; echo after"] {
+            let output = i.capture(input).unwrap();
+            assert_eq!(output.stdout, b"");
+        }
+        assert_eq!(i.run("sh -c 'kill -INT $$' && echo a").unwrap().code(), 130);
+        assert_eq!(i.capture("sh -c 'exit 130'; echo after").unwrap().stdout, b"after\n");
+        assert_eq!(i.capture("echo again").unwrap().stdout, b"again\n");
+    }
+
+    #[test]
+    fn test_traps() {
+        let mut i = Interpreter::new();
+        let output = i.capture("trap 'echo err' ERR; false; echo $?; true && false || true; ! false; trap -p; trap - ERR; false").unwrap();
+        assert_eq!(output.stdout, b"err\n1\ntrap -- 'echo err' ERR\n");
+        assert_eq!(i.capture("trap 'echo dbg' DEBUG; echo a; trap - DEBUG; echo b").unwrap().stdout, b"dbg\na\ndbg\nb\n");
+        // Traps run on the shell's streams rather than those of the pipeline stage they precede.
+        let output = i.capture("trap 'echo dbg' DEBUG; echo x | tr a-z A-Z; trap - DEBUG").unwrap();
+        assert_eq!(output.stdout, b"dbg\ndbg\nX\ndbg\n");
+        let output = i.capture("trap 'echo int' INT; sh -c 'kill -INT $$'; echo after; trap - INT").unwrap();
+        assert_eq!(output.stdout, b"int\nafter\n");
+        let output = i.capture("trap 'echo x' NOPE; echo $?").unwrap();
+        assert_eq!((output.stdout, output.stderr), (b"1\n".to_vec(), b"trap: NOPE: invalid signal specification\n".to_vec()));
+        assert_eq!(i.capture("trap 'echo bye' EXIT; exit 3; echo no").unwrap().stdout, b"");
+        assert_eq!(i.exited().map(|status| status.code()), Some(3));
+        let output = i.capture("trap -p EXIT").unwrap();
+        assert_eq!(output.stdout, b"trap -- 'echo bye' EXIT\n");
+    }
+
+    #[test]
+    fn test_set_options() {
+        let mut i = Interpreter::new();
+        let output = i.capture("set -o pipefail; set -o | grep -e pipefail -e xtrace; set +o | grep pipefail").unwrap();
+        assert_eq!(output.stdout, b"pipefail       \ton\nxtrace         \toff\nset -o pipefail\n");
+        assert_eq!(i.run("set -o nope").unwrap_err().to_string(), "set: nope: invalid option name");
+        assert_eq!(i.run("set -z").unwrap_err().to_string(), "set: -z: invalid option");
+        assert_eq!(i.run("set +Z").unwrap_err().to_string(), "set: +Z: invalid option");
+        assert_eq!(i.capture("echo $-; set -Cuo xtrace +x; echo $-").unwrap().stdout, b"\nCu\n");
+        assert_eq!(i.capture("set -- a 'b c'; echo $# $2; set +C x; echo $# $1; set --; echo $#").unwrap().stdout, b"2 b c\n1 x\n0\n");
+    }
+
+    #[test]
+    fn test_errexit() {
+        let mut i = Interpreter::new();
+        let output = i.capture("set -e; false && true; false || true; ! true; echo a; true && false; echo b").unwrap();
+        assert_eq!(output.stdout, b"a\n");
+        assert_eq!(i.exited().map(|status| status.code()), Some(1));
+        let output = i.capture("set -o pipefail; false | true; echo c").unwrap();
+        assert_eq!(output.stdout, b"");
+        assert_eq!(i.capture("set +e; false; echo d").unwrap().stdout, b"d\n");
+    }
+
+    #[test]
+    fn test_nounset() {
+        let mut i = Interpreter::new();
+        let output = i.capture("set -u; echo ${UNSET_VARIABLE}; echo $# after").unwrap();
+        assert_eq!((output.stdout, messages(&output.stderr)), (b"".to_vec(), "UNSET_VARIABLE: unbound variable\n".to_string()));
+        assert_eq!(i.exited(), Some(ExitStatus::FAILURE));
+        let output = i.capture("[[ -n $UNSET_VARIABLE ]] || echo after").unwrap();
+        assert_eq!((output.stdout, i.exited()), (b"".to_vec(), Some(ExitStatus::FAILURE)));
+        i.unset_var("-");
+        assert_eq!(i.capture("echo $UNSET_VARIABLE").unwrap_err().to_string(), "UNSET_VARIABLE: unbound variable");
+        assert_eq!(i.capture("set -u; echo ${UNSET_VARIABLE:-default}; set +u").unwrap().stdout, b"default\n");
+        assert_eq!(i.capture("set +u; echo ${UNSET_VARIABLE}x").unwrap().stdout, b"x\n");
+    }
+
+    #[test]
+    fn test_xtrace() {
+        let mut i = Interpreter::new();
+        let output = i.capture("set -x; A='a b' echo $((1 + 1)) 'c d' ''; PS4='$A> '; A=e; echo f; set +x; echo g").unwrap();
+        assert_eq!(output.stdout, b"2 c d \nf\ng\n");
+        assert_eq!(output.stderr, b"+ A='a b' echo 2 'c d' ''\n+ PS4='$A> '\n> A=e\ne> echo f\n");
+    }
+
+    #[test]
+    fn test_noexec() {
+        let mut i = Interpreter::new();
+        assert_eq!(i.capture("set -n; echo a; set +n; echo b").unwrap().stdout, b"");
+        assert!(i.capture("set -n; echo a;;").is_err());
+    }
+
+    #[test]
+    fn test_source() {
+        let dir = std::env::temp_dir().join(format!("rsh-source-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("rc"), "A=1\nfalse &&\n  false\nB=$((A + 1))\n").unwrap();
+        std::fs::write(dir.join("exits"), "exit 3\nC=1\n").unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).build().unwrap();
+        assert!(i.source("rc").unwrap().success());
+        assert_eq!(i.get_var("B"), Some("2"));
+        assert_eq!(i.source("missing").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(i.source(dir.join("exits")).unwrap().code(), 3);
+        assert_eq!((i.exited().map(|status| status.code()), i.get_var("C")), (Some(3), None));
+        assert_eq!(i.expand("~/$((1 + 1))").unwrap(), format!("{}/2", i.get_var("HOME").unwrap()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_source_builtin() {
+        let dir = std::env::temp_dir().join(format!("rsh-source-builtin-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib/module"), "echo \"$# $1\"\nA=set\n[[ $1 = stop ]] && return 4\necho end\n").unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).args(["x"]).build().unwrap();
+        let output = i.capture("trap 'echo returned' RETURN; source lib/module a b; echo $# $1 $A; PATH=$PWD/lib:$PATH; . module stop; echo $?").unwrap();
+        assert_eq!(output.stdout, b"2 a\nend\nreturned\n1 x set\n1 stop\nreturned\n4\n");
+        let output = i.capture("source; . missing; return; echo next").unwrap();
+        assert_eq!(messages(&output.stderr), "source: filename argument required\nsource: missing: No such file or directory\nreturn: can only `return' from a sourced file\n");
+        assert_eq!(output.stdout, b"next\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut i = Interpreter::new();
+        let output = i.capture("alias greet='echo hello' loud='greet '; alias say=greet 'bad name=x'; alias greet nope").unwrap();
+        assert_eq!(output.stdout, b"alias greet='echo hello'\n");
+        assert_eq!(output.stderr, b"alias: `bad name=x': invalid alias name\nalias: nope: not found\n");
+        let output = i.capture("greet world; say again && echo greet; loud loud; alias").unwrap();
+        assert_eq!(output.stdout, b"hello world\nhello again\ngreet\nhello echo hello\nalias greet='echo hello'\nalias loud='greet '\nalias say='greet'\n");
+        let output = i.capture("unalias say nope; alias -p | wc -l; unalias -a; alias").unwrap();
+        assert_eq!((output.stdout, output.stderr), (b"2\n".to_vec(), b"unalias: nope: not found\n".to_vec()));
+        // Aliases are expanded as each line is compiled, so changes apply from the next line.
+        assert_eq!(i.capture("greet").unwrap_err().to_string(), "greet: command not found");
+        assert!(i.capture("unalias").is_err());
+        let dir = std::env::temp_dir().join(format!("rsh-aliases-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).build().unwrap();
+        let output = i.capture("alias ll='echo LL'; ll same\nll next; alias > list; unalias ll\n. ./list\nll again").unwrap();
+        assert_eq!((output.stdout, messages(&output.stderr)), (b"LL next\nLL again\n".to_vec(), "ll: command not found\n".to_string()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_noclobber() {
+        let dir = std::env::temp_dir().join(format!("rsh-noclobber-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).build().unwrap();
+        let output = i.capture("set -C; echo a > file; echo b > file; echo c >> file; cat file; echo d >| file; echo e > /dev/null; cat file").unwrap();
+        assert_eq!(output.stdout, b"a\nc\nd\n");
+        assert_eq!(messages(&output.stderr), "file: cannot overwrite existing file\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_redirects() {
+        let dir = std::env::temp_dir().join(format!("rsh-redirects-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).build().unwrap();
+        let output = i.capture("exec 3>out; echo a >&3; sh -c 'echo b >&4' 4>&3; echo c 3>&- >&3; echo d 2>err 1>&2; cat <err; ls missing 2>&1 >/dev/null | wc -l; exec 3>&-; cat out").unwrap();
+        assert_eq!(output.stdout, b"d\n1\na\nb\n");
+        assert_eq!(messages(&output.stderr), "3: Bad file descriptor\n");
+        assert!(!dir.join("&3").exists());
+        let output = i.capture("exec 20>many; echo e >&20; exec 20>&- 3<many; cat<many; cat 0<&3; exec 3<&-").unwrap();
+        assert_eq!(output.stdout, b"e\ne\n");
+        assert_eq!(i.capture("echo f 99999999999>x").unwrap_err().to_string(), "99999999999: Bad file descriptor");
+        assert_eq!(i.capture("echo f > < x").unwrap_err().to_string(), "syntax error near unexpected token `<'");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_exec() {
+        let dir = std::env::temp_dir().join(format!("rsh-exec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("input"), "first\nsecond\n").unwrap();
+        let mut i = Interpreter::builder().cwd(&dir).build().unwrap();
+        let output = i.capture("exec 3>out 4<input; sh -c 'echo a >&3'; exec 5<&4 3>>out; sh -c 'head -n 1 <&5; echo b >&3'; exec 3>&- 5>&-; cat out").unwrap();
+        assert_eq!((output.stdout, output.stderr), (b"first\na\nb\n".to_vec(), b"".to_vec()));
+        let output = i.capture("sh -c 'echo c >&3'; exec 6>&3; exec 2>&-; echo next").unwrap();
+        assert!(messages(&output.stderr).ends_with("3: Bad file descriptor\n2: cannot close a standard stream\n"));
+        assert_eq!(output.stdout, b"next\n");
+        assert_eq!(i.capture("exec 3>").unwrap_err().to_string(), "unexpected EOF");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut i = Interpreter::new();
+        i.register_builtin("upper", |_: &[String], _: &mut Environment, stdio: &mut BuiltinStdio| {
+            let mut input = String::new();
+            stdio.stdin.read_to_string(&mut input).unwrap();
+            write!(stdio.stdout, "{}", input.to_uppercase()).unwrap();
+            ExitStatus::SUCCESS
+        });
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        assert!(i.stream("upper", b"abc", &mut stdout, &mut stderr).unwrap().success());
+        assert!(i.stream("cat | wc -c", &[b'x'; 100_000], &mut stdout, &mut stderr).unwrap().success());
+        assert!(i.stream("true", &[b'x'; 100_000], &mut stdout, &mut stderr).unwrap().success());
+        assert_eq!(String::from_utf8(stdout).unwrap().split_whitespace().collect::<Vec<&str>>(), vec!["ABC100000"]);
+        assert_eq!(stderr, b"");
+    }
+
+    #[test]
+    fn test_mock_spawner_records() {
+        let mock = MockSpawner::new();
+        mock.script("ls", Scripted{ stdout: b"a\nb\n".to_vec(), ..Default::default() });
+        mock.script("wc", Scripted{ stdout: b"2\n".to_vec(), stderr: b"counted\n".to_vec(), ..Default::default() });
+        let mut i = Interpreter::builder()
+            .env(vec![("HOME", "/home/rsh")])
+            .cwd("/")
+            .spawner(mock.clone())
+            .build()
+            .unwrap();
+        let output = i.capture("LC_ALL=C ls -l ~/'a b' | wc -l").unwrap();
+        assert_eq!((output.stdout, output.stderr, output.status), (b"2\n".to_vec(), b"counted\n".to_vec(), ExitStatus::SUCCESS));
+        let records = mock.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].argv, vec!["ls", "-l", "/home/rsh/a b"]);
+        assert_eq!(records[0].env.get("LC_ALL").map(String::as_str), Some("C"));
+        assert_eq!(records[0].env.get("HOME").map(String::as_str), Some("/home/rsh"));
+        assert_eq!(records[0].cwd, PathBuf::from("/"));
+        assert_eq!((records[0].stdout, records[0].stderr), (StreamKind::Fd, StreamKind::Fd));
+        assert_eq!(records[1].argv, vec!["wc", "-l"]);
+        assert_eq!(records[1].env.get("LC_ALL"), None);
+        assert_eq!(records[1].stdin, Some(b"a\nb\n".to_vec()));
+        assert_eq!(records[1].stdout, StreamKind::Fd);
+    }
+
+    #[test]
+    fn test_mock_spawner_large_output() {
+        let mock = MockSpawner::new();
+        mock.script("big", Scripted{ stdout: vec![b'x'; 200 * 1024], ..Default::default() });
+        mock.script("wc", Scripted{ stdout: b"204800\n".to_vec(), ..Default::default() });
+        let mut i = Interpreter::builder().spawner(mock.clone()).build().unwrap();
+        assert_eq!(i.capture("big | wc -c").unwrap().stdout, b"204800\n");
+        assert_eq!(mock.records()[1].stdin.as_ref().map(Vec::len), Some(200 * 1024));
+    }
+
+    #[test]
+    fn test_mock_spawner_statuses() {
+        let mock = MockSpawner::new();
+        mock.script("check", Scripted{ status: ExitStatus::new(1), ..Default::default() });
+        mock.script("check", Scripted::default());
+        mock.script("deploy", Scripted{ status: ExitStatus::new(3), ..Default::default() });
+        let mut i = Interpreter::builder().spawner(mock.clone()).build().unwrap();
+        assert!(i.interpret("check || check").unwrap());
+        assert!(i.interpret("check && cd /tmp && check").unwrap());
+        assert_eq!(i.cwd(), Path::new("/tmp"));
+        assert_eq!(i.run("deploy > /dev/null").unwrap().code(), 3);
+        assert_eq!(i.run("missing").unwrap_err().to_string(), "missing: command not found");
+        let records = mock.records();
+        assert_eq!(records.iter().map(|record| record.argv[0].as_str()).collect::<Vec<&str>>(), vec!["check", "check", "check", "check", "deploy", "missing"]);
+        assert_eq!(records[3].cwd, PathBuf::from("/tmp"));
+        assert_eq!(records[4].stdout, StreamKind::Fd);
+        assert_eq!(records[4].stdin, None);
+    }
+
+    #[test]
+    fn test_mock_spawner_exec() {
+        let mock = MockSpawner::new();
+        mock.script("wrapped", Scripted{ status: ExitStatus::new(5), ..Default::default() });
+        let mut i = Interpreter::builder().spawner(mock.clone()).build().unwrap();
+        assert_eq!(i.run("exec 3>/dev/null; FOO=1 exec wrapped -v; echo unreachable").unwrap().code(), 5);
+        assert_eq!(i.exited(), Some(ExitStatus::new(5)));
+        let records = mock.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].argv.clone(), records[0].fds.clone()), (vec!["wrapped".to_string(), "-v".to_string()], vec![3]));
+        assert_eq!(records[0].env.get("FOO").map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn test_mock_spawner_signals() {
+        let mock = MockSpawner::new();
+        let mut i = Interpreter::builder().spawner(mock.clone()).build().unwrap();
+        assert!(i.interpret("kill -USR2 999999999 && kill -9 -1").unwrap());
+        assert_eq!(mock.signals(), vec![(999_999_999, libc::SIGUSR2), (-1, libc::SIGKILL)]);
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let mock = MockSpawner::new();
+        mock.script("ls", Scripted::default());
+        let mut i = Interpreter::builder().spawner(mock).build().unwrap();
+        let err = i.run("ls && X=1 nope -l").unwrap_err();
+        assert_eq!((err.to_string(), err.span(), err.exit_code()), ("nope: command not found".to_string(), Some(Span::new(10, 14)), 127));
+        let err = i.run("ls\nls ${a b} c").unwrap_err();
+        assert_eq!((err.span(), err.exit_code()), (Some(Span::new(6, 12)), 1));
+        let err = i.run("ls > /no/such/dir/file").unwrap_err();
+        assert!(matches!(err, InterpreterError::Redirection{..}));
+        assert_eq!(err.span(), Some(Span::new(0, 22)));
+        let err = i.run("ls |").unwrap_err();
+        assert_eq!((err.to_string(), err.span(), err.exit_code()), ("unexpected EOF".to_string(), Some(Span::new(4, 4)), 2));
+        let err = i.run("ls; && ls").unwrap_err();
+        assert_eq!((err.to_string(), err.span()), ("syntax error near unexpected token `&&'".to_string(), Some(Span::new(4, 6))));
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        let mut i = Interpreter::builder().spawner(MockSpawner::new()).build().unwrap();
+        assert_eq!(i.run("$EMPTY; X=1 $EMPTY").unwrap(), ExitStatus::SUCCESS);
+        assert_eq!(i.get_var("X"), Some("1"));
+        i.unset_var("PWD");
+        i.unset_var("HOME");
+        assert!(i.run("pushd -n /; dirs; cd ~; cd -").is_ok());
+        for input in ["|", "&& x", "x ||", ";;", ">", "x >", "$((", "${", "[[", "[[ ( ]]", "((", "'", "\\"] {
+            let _ = i.run(input);
+        }
+    }
+
+    #[test]
+    fn test_list_dirs() {
+        let mut env = Environment::new();
+        env.insert("HOME".to_string(), "/home/rsh".to_string());
+        let dirs = vec!["/home/rsh/src".to_string(), "/home/rsh".to_string(), "/home/rshx".to_string()];
+        assert_eq!(list_dirs(&dirs, &env, false, false, false), "~/src ~ /home/rshx");
+        assert_eq!(list_dirs(&dirs, &env, true, true, false), "/home/rsh/src\n/home/rsh\n/home/rshx");
+        assert_eq!(list_dirs(&dirs[..2], &env, false, false, true), " 0  ~/src\n 1  ~");
+    }
+
+    #[test]
+    fn aasdasd() {
+        println!("{:?}", "a=b".split('=').map(str::to_string).collect::<Vec<String>>());
+        println!("{:?}", "a".split('=').map(str::to_string).collect::<Vec<String>>());
+        println!("{:?}", "a=".split('=').map(str::to_string).collect::<Vec<String>>());
+        println!("{:?}", "=a".split('=').map(str::to_string).collect::<Vec<String>>());
+        println!("{:?}", "=".split('=').map(str::to_string).collect::<Vec<String>>());
+        println!("{:?}", "".split('=').map(str::to_string).collect::<Vec<String>>());
+    }
+}
\ No newline at end of file
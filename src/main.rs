@@ -1,351 +1,173 @@
-use bumpalo::Bump;
-use shlex::Shlex;
-use std::iter::Peekable;
-use std::process::Stdio;
+use rsh::Interpreter;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
 
-mod substitution;
-mod errors;
-mod compiler;
-mod physical;
-use errors::*;
-use physical::*;
-use std::fs::OpenOptions;
-use std::collections::HashMap;
+const USAGE: &str = "usage: rsh [-l] [--norc] [--noprofile] [-c command [name [arg ...]] | file [arg ...]]";
 
-use substitution::substitution;
+/// The startup file every interactive shell runs before the user's own.
+const SYSTEM_RC: &str = "/etc/rshrc";
 
-pub struct Interpreter {
-    environment: Environment
-}
-
-type Lexer<'a> = Peekable<Shlex<'a>>;
-
-impl <'a> Interpreter {
-
-    pub fn new() -> Self {
-        Interpreter { environment: std::env::vars().collect() }
-    }
-
-    pub fn interpret<I: AsRef<str>>(&'a mut self, input: I) -> InterpreterResult<bool> {
-        let arena = Bump::new();
-        let lexer = Shlex::new(input.as_ref()).peekable();
-        let ast = Self::compile(&arena, lexer, &mut self.environment)?;
-        Ok(ast.eval()?.wait())
-
-    }
-
-    fn compile(arena: &'a Bump, mut lexer: Lexer, env: &mut Environment) -> InterpreterResult<ArenaStatement<'a>> {
-        let mut tokens = vec![];
-        while let Some(token) = lexer.next() {
-            match token.as_str() {
-                "&&" => return Ok(Self::alloc(arena, And::new(Self::compile_expression(arena, env, tokens)?, Self::compile(arena, lexer, env)?))),
-                "||" => return Ok(Self::alloc(arena, Or::new(Self::compile_expression(arena, env, tokens)?, Self::compile(arena, lexer, env)?))),
-                "|" => return Ok(Self::alloc(arena, Pipe::new(Self::compile_expression(arena, env, tokens)?, Self::compile(arena, lexer, env)?))),
-                _ => tokens.push(token.clone()),
-            }
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    // A login shell is started with `-l`, or by login(1) with a `-` before its name.
+    let mut startup = Startup{ login: args[0].starts_with('-'), ..Startup::default() };
+    let mut operands = &args[1..];
+    while let Some(option) = operands.first() {
+        match option.as_str() {
+            "-l" | "--login" => startup.login = true,
+            "--norc" => startup.norc = true,
+            "--noprofile" => startup.noprofile = true,
+            _ => break
         }
-        return Self::compile_expression(arena, env, tokens);
-    }
-
-    fn compile_expression(arena: &'a Bump, env: &mut Environment, tokens: Vec<String>) -> InterpreterResult<ArenaStatement<'a>> {
-        match tokens.iter().map(|t| t.as_str()).collect::<Vec<&str>>().as_slice() {
-            [] => {
-                Err(InterpreterError{message: "unexpected EOF".to_string()})
-            },
-            [_, ">"] => {
-                Err(InterpreterError{message: "unexpected EOF".to_string()})
-            },
-            [_, ">>"] => {
-                Err(InterpreterError{message: "unexpected EOF".to_string()})
-            },
-            [">"] => {
-                Err(InterpreterError{message: "unexpected EOF".to_string()})
-            },
-            [">>"] => {
-                Err(InterpreterError{message: "unexpected EOF".to_string()})
-            },
-            ["cd"] =>  {
-                Ok(Self::alloc(arena, CD::new(None, env)))
-            },
-            ["cd", path] => {
-                Ok(Self::alloc(arena, CD::new(Some(path.to_string()), env)))
-            },
-            ["export", kvs@..] => Ok(Self::alloc(arena, Self::update_env(kvs, env)?)),
-            [head@.., ">", target] => {
-                Ok(Self::alloc(arena, Redirect::new(Command::new(head, env), RedirectType::Truncate, target.to_string())))
-            },
-            [head@.., ">>", target] => {
-                Ok(Self::alloc(arena, Redirect::new(Command::new(head, env), RedirectType::Append, target.to_string())))
-            },
-            [command@..] => {
-                Ok(Self::alloc(arena, Command::new(command, env)))
+        operands = &operands[1..];
+    }
+    let status = match operands.first().map(String::as_str) {
+        Some("-c") => match operands.get(1) {
+            Some(command) => run(operands.get(2).unwrap_or(&args[0]), &operands[operands.len().min(3)..], command, &startup),
+            None => {
+                eprintln!("rsh: -c: option requires an argument\n{}", USAGE);
+                2
             }
-        }
-    }
-
-    fn alloc<T: Statement + 'a>(arena: &'a Bump, val: T) -> ArenaStatement<'a> {
-        Box::new(arena.alloc(val))
-    }
-
-    fn update_env(pairs: &[&str], env: &mut Environment) -> InterpreterResult<NOOP>  {
-        for pair in pairs {
-            if pair.starts_with('=') {
-                return Err(InterpreterError{message:format!("export: '{}`: not a valid identifier", pair)})
+        },
+        Some(file) => match std::fs::read_to_string(file) {
+            Ok(script) => run(file, &operands[1..], &script, &startup),
+            Err(err) => {
+                eprintln!("rsh: {}: {}", file, err);
+                127
             }
-            let mut kv: Vec<String> = pair.split('=').map(str::to_string).collect();
-            env.insert(kv.remove(0), kv.pop().unwrap_or("".to_string()));
-        }
-        Ok(NOOP{})
-    }
-}
-
-struct NOOP {}
-
-impl Statement for NOOP {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>> { Ok(Box::new(NOOP{})) }
-    fn set_stdin(&mut self, _: Stdio) {}
-    fn set_stdout(&mut self, _: fn() -> Stdio) {}
-}
-
-impl Process for NOOP {
-    fn get_stdout(self: Box<Self>) -> Stdio { Stdio::null() }
-    fn wait(&mut self) -> bool { true }
-}
-
-type Environment = HashMap<String, String>;
-
-pub type Program<'a> = ArenaStatement<'a>;
-
-struct Command {
-    inner: std::process::Command
-}
-
-impl Command {
-    fn new<S: ToString, T: IntoIterator<Item=S>>(tokens: T, env: &mut Environment) -> Command {
-        let tokens: Vec<String> = tokens.into_iter().map(|i| i.to_string()).collect();
-        let mut expanded = vec![];
-        let mut iter = tokens.iter();
-        while let Some(pair) = iter.next() {
-            if pair.starts_with('=') || !pair.contains('=') {
-                expanded.push(substitution(pair, env).unwrap());
-                break;
+        },
+        None => repl(&args[0], &startup)
+    };
+    std::process::exit(status);
+}
+
+/// Which startup files the shell runs, as chosen by its options.
+#[derive(Default)]
+struct Startup {
+    login: bool,
+    /// Skips the rc files and `$ENV`.
+    norc: bool,
+    /// Skips the login profile.
+    noprofile: bool
+}
+
+impl Startup {
+    /// Runs the profile of a login shell, then the rc files of an interactive one followed
+    /// by the file named in `$ENV`. Files that do not exist are skipped, and errors in the
+    /// others are reported without stopping the shell from starting.
+    fn run(&self, interpreter: &mut Interpreter, interactive: bool) {
+        if self.login && !self.noprofile {
+            if let Some(home) = home(interpreter) {
+                load(interpreter, home.join(".rsh_profile"));
             }
-            let mut kv: Vec<String> = pair.split('=').map(str::to_string).collect();
-            env.insert(kv.remove(0), substitution(kv.pop().unwrap_or("".to_string()), env).unwrap());
         }
-        while let Some(token) = iter.next() {
-            expanded.push(substitution(token, env).unwrap());
+        if !interactive || self.norc {
+            return;
         }
-        let mut inner = std::process::Command::new(expanded.remove(0));
-        inner.args(expanded);
-        inner.envs(env);
-        Command{inner}
-    }
-}
-
-impl Statement for Command {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>> {
-        Ok(Box::new(CommandProcess{ child: self.inner.spawn()?, result: None }))
-    }
-    fn set_stdin(&mut self, stdin: Stdio) {
-        self.inner.stdin(stdin);
-    }
-    fn set_stdout(&mut self, stdout: fn() -> Stdio) {
-        self.inner.stdout(stdout());
-    }
-}
-
-struct And<'a> {
-    lhs: ArenaStatement<'a>,
-    rhs: ArenaStatement<'a>,
-}
-
-impl <'a> And<'a> {
-    fn new(lhs: ArenaStatement<'a>, rhs: ArenaStatement<'a>) -> And<'a> {
-        return And{lhs, rhs}
-    }
-}
-
-impl Statement for And<'_> {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>> {
-        if !self.lhs.eval()?.wait() {
-            return Err(InterpreterError{message:"".to_string()});
+        load(interpreter, PathBuf::from(SYSTEM_RC));
+        if let Some(home) = home(interpreter) {
+            load(interpreter, home.join(".rshrc"));
         }
-        self.rhs.eval()
-    }
-    fn set_stdin(&mut self, stdin: Stdio) {
-        self.lhs.set_stdin(stdin);
-    }
-    fn set_stdout(&mut self, stdout: fn() -> Stdio) {
-        self.rhs.set_stdout(stdout);
-    }
-}
-
-struct Or<'a> {
-    lhs: ArenaStatement<'a>,
-    rhs: ArenaStatement<'a>,
-}
-
-impl <'a> Or<'a> {
-    fn new(lhs: ArenaStatement<'a>, rhs: ArenaStatement<'a>) -> Or<'a> {
-        return Or{lhs, rhs}
-    }
-}
-
-impl Statement for Or<'_> {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>> {
-        match self.lhs.eval() {
-            Ok(mut p) => { if p.wait() { return Ok(p) } }
-            Err(err) => eprintln!("{}", err)
+        // The profile may have set `ENV`, which is expanded as POSIX requires.
+        if let Some(env) = interpreter.get_var("ENV").map(str::to_string) {
+            match interpreter.expand(env) {
+                Ok(path) if !path.is_empty() => load(interpreter, PathBuf::from(path)),
+                Ok(_) => {}
+                Err(err) => eprintln!("rsh: ENV: {}", err)
+            }
         }
-        self.rhs.eval()
-    }
-    fn set_stdin(&mut self, stdin: Stdio) {
-        self.lhs.set_stdin(stdin);
     }
-    fn set_stdout(&mut self, stdout: fn() -> Stdio) {
-        self.lhs.set_stdout(stdout);
-        self.rhs.set_stdout(stdout);
-    }
-}
-
-struct Pipe<'a> {
-    lhs: ArenaStatement<'a>,
-    rhs: ArenaStatement<'a>,
 }
 
-impl <'a> Pipe<'a> {
-    fn new(lhs: ArenaStatement<'a>, rhs: ArenaStatement<'a>) -> Pipe<'a> {
-        return Pipe{lhs, rhs}
-    }
+/// The directory the user's startup files are in.
+fn home(interpreter: &Interpreter) -> Option<PathBuf> {
+    interpreter.get_var("HOME").map(PathBuf::from).or_else(dirs::home_dir)
 }
 
-impl Statement for Pipe<'_> {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>> {
-        self.lhs.set_stdout(Stdio::piped);
-        self.rhs.set_stdin(self.lhs.eval()?.get_stdout());
-        self.rhs.eval()
-    }
-    fn set_stdin(&mut self, stdin: Stdio) {
-        self.lhs.set_stdin(stdin);
+/// Runs a startup file, unless an earlier one has exited the shell.
+fn load(interpreter: &mut Interpreter, path: PathBuf) {
+    if interpreter.exited().is_some() {
+        return;
     }
-    fn set_stdout(&mut self, stdout: fn() -> Stdio) {
-        self.rhs.set_stdout(stdout)
+    match interpreter.source(&path) {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => eprintln!("rsh: {}: {}", path.display(), err)
     }
 }
 
-struct Redirect {
-    cmd: Command,
-    target: String,
-    _type: RedirectType
-}
-
-#[derive(Clone, Copy)]
-enum RedirectType {
-    Append,
-    Truncate
-}
-
-impl Into<bool> for RedirectType {
-    fn into(self) -> bool {
-        match self {
-            RedirectType::Append => false,
-            RedirectType::Truncate => true
+/// Creates the interpreter, with job control if it is interactive.
+fn interpreter(name: &str, args: &[String], interactive: bool) -> Interpreter {
+    match Interpreter::builder().name(name).args(args).option("monitor", interactive).build() {
+        Ok(interpreter) => interpreter,
+        Err(err) => {
+            eprintln!("rsh: {}", err);
+            std::process::exit(1);
         }
     }
 }
 
-type ArenaStatement<'a> = Box<&'a mut dyn Statement>;
-
-impl Redirect {
-    fn new(cmd: Command, _type: RedirectType, target: String) -> Redirect {
-        return Redirect{cmd, _type, target}
-    }
-}
-
-impl Statement for Redirect {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>> {
-        let target = physical::expand(&self.target);
-        let mut opts = OpenOptions::new();
-        opts.write(true).create(true);
-        match self._type {
-            RedirectType::Append => opts.append(true),
-            RedirectType::Truncate => opts.truncate(true)
-        };
-        let file = opts.open(target)?;
-        self.cmd.inner.stdout(Stdio::from(file));
-        self.cmd.eval()
-    }
-    fn set_stdin(&mut self, stdin: Stdio) {
-        self.cmd.inner.stdin(stdin);
+fn run(name: &str, args: &[String], script: &str, startup: &Startup) -> i32 {
+    let mut interpreter = interpreter(name, args, false);
+    startup.run(&mut interpreter, false);
+    if interpreter.exited().is_some() {
+        return finish(&mut interpreter, 0);
     }
-    fn set_stdout(&mut self, _: fn() -> Stdio) {}
-}
-
-struct CD {
-    target: Option<String>,
-}
-
-impl CD {
-    fn new(target: Option<String>, env: &Environment) -> CD {
-        let target = if let Some(path) = target {
-            Some(substitution(path, env).unwrap())
-        } else {
-            None
+    let status = match interpreter.run(script) {
+        Ok(status) => status.code(),
+        Err(err) => {
+            eprintln!("rsh: {}", err.diagnostic(script));
+            err.exit_code()
+        }
+    };
+    finish(&mut interpreter, status)
+}
+
+/// Runs the `EXIT` trap, returning the status to exit with: `status`, unless the trap
+/// runs `exit` itself.
+fn finish(interpreter: &mut Interpreter, status: i32) -> i32 {
+    interpreter.finish();
+    interpreter.exited().map_or(status, |status| status.code())
+}
+
+/// Reads commands from stdin one line at a time, prompting with `PS1` when stdin is a
+/// terminal.
+fn repl(name: &str, startup: &Startup) -> i32 {
+    let interactive = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    let mut interpreter = interpreter(name, &[], interactive);
+    startup.run(&mut interpreter, interactive);
+    if interpreter.exited().is_some() {
+        return finish(&mut interpreter, 0);
+    }
+    let stdin = std::io::stdin();
+    let mut status = 0;
+    loop {
+        if interactive {
+            interpreter.notify();
+            eprint!("{}", interpreter.get_var("PS1").unwrap_or("$ "));
+            let _ = std::io::stderr().flush();
+        }
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => return finish(&mut interpreter, status),
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("rsh: {}", err);
+                return finish(&mut interpreter, 1);
+            }
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        status = match interpreter.run(&line) {
+            Ok(status) => status.code(),
+            Err(err) => {
+                eprintln!("rsh: {}", err.diagnostic(&line));
+                err.exit_code()
+            }
         };
-        return CD{target}
-    }
-}
-
-impl Statement for CD {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>> {
-       let mut p = CDProcess{ target: self.target.clone(), result: None };
-        if !p.wait() {
-            return Err(InterpreterError{message: "".to_string()})
+        if interpreter.exited().is_some() {
+            return finish(&mut interpreter, status);
         }
-        Ok(Box::new(p))
     }
-
-    fn set_stdin(&mut self, _: Stdio) {}
-
-    fn set_stdout(&mut self, _: fn() -> Stdio) {}
 }
-
-pub trait Statement {
-    fn eval(&mut self) -> InterpreterResult<Box<dyn Process>>;
-    fn set_stdin(&mut self, stdin: Stdio);
-    fn set_stdout(&mut self, stdout: fn() -> Stdio);
-}
-
-
-fn main() {
-    // match Compiler::new().compile(Shlex::new("cd && cd .. && ls | rg chris").peekable()).unwrap().unwrap().eval() {
-    //     Ok(mut p) => {p.wait();},
-    //     Err(err) => {eprintln!("{}", err);}
-    // };
-    let mut i = Interpreter::new();
-    // i.interpret("ls | rg src > ls && whoami && pwds || ls").unwrap();
-    // i.interpret("ls -l && ls -z || ls -a").unwrap();
-    // i.interpret("export MINE YOLO=BAZ");
-    // i.interpret("env | rg MINE");
-    // i.interpret("env | rg YOLO");
-    // i.interpret("echo $YOLO");
-    i.interpret("export CWD=derp");
-    i.interpret("cd /tmp/${CWD} && ls").unwrap();
-    // println!("{:?}", shlex::split("ls ; ls"));
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    
-    #[test]
-    fn aasdasd() {
-        println!("{:?}", "a=b".split('=').map(str::to_string).collect::<Vec<String>>());
-        println!("{:?}", "a".split('=').map(str::to_string).collect::<Vec<String>>());
-        println!("{:?}", "a=".split('=').map(str::to_string).collect::<Vec<String>>());
-        println!("{:?}", "=a".split('=').map(str::to_string).collect::<Vec<String>>());
-        println!("{:?}", "=".split('=').map(str::to_string).collect::<Vec<String>>());
-        println!("{:?}", "".split('=').map(str::to_string).collect::<Vec<String>>());
-    }
-}
\ No newline at end of file
@@ -0,0 +1,108 @@
+/// The options that have a single letter flag of their own, in the order `$-` lists them.
+const FLAGS: &[(char, &str)] = &[
+    ('C', "noclobber"),
+    ('e', "errexit"),
+    ('f', "noglob"),
+    ('m', "monitor"),
+    ('n', "noexec"),
+    ('u', "nounset"),
+    ('x', "xtrace")
+];
+
+/// The shell options turned on and off with `set -o name` and `set +o name`, or with the
+/// single letter flags of those that have one.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// `-e`: the shell exits as soon as a pipeline fails, unless its status is tested by
+    /// `&&`, `||` or `!`.
+    pub errexit: bool,
+    /// Job control: each pipeline runs in a process group of its own, which may be stopped
+    /// and resumed. Interactive shells turn it on.
+    pub monitor: bool,
+    /// `-C`: `>` refuses to overwrite an existing regular file, although `>|` still may.
+    pub noclobber: bool,
+    /// `-n`: commands are read and checked for syntax errors but not run. Interactive
+    /// shells ignore it.
+    pub noexec: bool,
+    /// `-f`: disables pathname expansion. The shell does not expand pathnames yet, so the
+    /// option is only recorded.
+    pub noglob: bool,
+    /// `-u`: expanding a variable or positional parameter that is not set is an error, which
+    /// exits a shell that is not interactive.
+    pub nounset: bool,
+    /// A pipeline fails with the status of its rightmost failing stage, rather than
+    /// reporting the status of its last stage.
+    pub pipefail: bool,
+    /// `-x`: each simple command is printed to standard error after `PS4` once it has been
+    /// expanded, before it runs.
+    pub xtrace: bool
+}
+
+impl Options {
+    /// Every option's name with whether it is enabled, in alphabetical order.
+    pub fn list(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("errexit", self.errexit),
+            ("monitor", self.monitor),
+            ("noclobber", self.noclobber),
+            ("noexec", self.noexec),
+            ("noglob", self.noglob),
+            ("nounset", self.nounset),
+            ("pipefail", self.pipefail),
+            ("xtrace", self.xtrace)
+        ]
+    }
+
+    /// Turns the option called `name` on or off, returning false if there is no such option.
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let option = match name {
+            "errexit" => &mut self.errexit,
+            "monitor" => &mut self.monitor,
+            "noclobber" => &mut self.noclobber,
+            "noexec" => &mut self.noexec,
+            "noglob" => &mut self.noglob,
+            "nounset" => &mut self.nounset,
+            "pipefail" => &mut self.pipefail,
+            "xtrace" => &mut self.xtrace,
+            _ => return false
+        };
+        *option = enabled;
+        true
+    }
+
+    /// Returns the name of the option whose single letter flag is `flag`.
+    pub fn name(flag: char) -> Option<&'static str> {
+        FLAGS.iter().find(|(letter, _)| *letter == flag).map(|&(_, name)| name)
+    }
+
+    /// The flags of the enabled options, which `$-` expands to.
+    pub fn flags(&self) -> String {
+        let enabled = self.list();
+        FLAGS.iter().filter(|(_, name)| enabled.contains(&(name, true))).map(|(flag, _)| flag).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut options = Options::default();
+        assert!(options.set("pipefail", true));
+        assert!(options.pipefail);
+        assert!(!options.set("nope", true));
+        assert_eq!(options.list().into_iter().filter(|(_, enabled)| *enabled).collect::<Vec<_>>(), vec![("pipefail", true)]);
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut options = Options::default();
+        assert_eq!(Options::name('e'), Some("errexit"));
+        assert_eq!(Options::name('o'), None);
+        assert!(options.set("xtrace", true));
+        assert!(options.set(Options::name('u').unwrap(), true));
+        assert!(options.set("pipefail", true));
+        assert_eq!(options.flags(), "ux");
+    }
+}
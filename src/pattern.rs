@@ -0,0 +1,127 @@
+const ESCAPE: char = '\\';
+const SPECIAL: &[char] = &['*', '?', '[', ']', ESCAPE];
+
+/// Matches `text` against a shell pattern supporting `*`, `?`, bracket expressions and
+/// backslash escapes.
+pub fn matches<P: AsRef<str>, T: AsRef<str>>(pattern: P, text: T) -> bool {
+    let pattern: Vec<char> = pattern.as_ref().chars().collect();
+    let text: Vec<char> = text.as_ref().chars().collect();
+    matches_at(&pattern, &text)
+}
+
+/// Escapes every character that `matches` would otherwise treat as special.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if SPECIAL.contains(&c) {
+            escaped.push(ESCAPE);
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn matches_at(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position to resume from when the most recent `*` must swallow another character.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let advanced = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match bracket(&pattern[p..], text[t]) {
+                Some((matched, len)) => if matched { Some(p + len) } else { None },
+                None => if text[t] == '[' { Some(p + 1) } else { None }
+            },
+            Some(&ESCAPE) if p + 1 < pattern.len() => if pattern[p + 1] == text[t] { Some(p + 2) } else { None },
+            Some(&c) => if c == text[t] { Some(p + 1) } else { None },
+            None => None
+        };
+        match advanced {
+            Some(next) => {
+                p = next;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, from)) => {
+                    p = star + 1;
+                    t = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the bracket expression at the start of `pattern`, returning whether
+/// it matched and the length of the expression, or `None` if the bracket is never closed.
+fn bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut idx = 1;
+    let negated = matches!(pattern.get(idx), Some('!') | Some('^'));
+    if negated {
+        idx += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut lo = *pattern.get(idx)?;
+        if lo == ']' && !first {
+            return Some((matched != negated, idx + 1));
+        }
+        first = false;
+        if lo == ESCAPE {
+            idx += 1;
+            lo = *pattern.get(idx)?;
+        }
+        if pattern.get(idx + 1) == Some(&'-') && pattern.get(idx + 2).is_some_and(|&hi| hi != ']') {
+            let hi = pattern[idx + 2];
+            matched |= lo <= c && c <= hi;
+            idx += 3;
+        } else {
+            matched |= lo == c;
+            idx += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal() {
+        assert!(matches("abc", "abc"));
+        assert!(!matches("abc", "abd"));
+        assert!(!matches("abc", "abcd"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("?b*", "abc"));
+        assert!(!matches("?b*", "bc"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn test_brackets() {
+        assert!(matches("[abc]x", "bx"));
+        assert!(matches("[a-z]*", "hello"));
+        assert!(!matches("[!a-z]*", "hello"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[", "["));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert!(matches(escape("a*b?"), "a*b?"));
+        assert!(!matches(escape("a*"), "abc"));
+    }
+}
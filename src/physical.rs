@@ -1,89 +1,309 @@
-use std::process::Stdio;
-use std::path::{PathBuf, Component, Path};
-use std::ffi::OsStr;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::os::unix::fs::MetadataExt;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
 
 pub trait Process {
-    fn get_stdout(self: Box<Self>) -> Stdio;
-    fn wait(&mut self) -> bool;
+    fn wait(&mut self) -> ExitStatus;
+
+    /// The process ID of a child process, which job control waits for itself. Processes
+    /// that are not children of the shell have none.
+    fn pid(&self) -> Option<libc::pid_t> {
+        None
+    }
+}
+
+/// The status a command finished with. As with `$?`, a command killed by a signal reports
+/// 128 plus the signal number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExitStatus {
+    code: i32,
+    signal: Option<i32>
+}
+
+impl ExitStatus {
+    pub const SUCCESS: ExitStatus = ExitStatus{code: 0, signal: None};
+    pub const FAILURE: ExitStatus = ExitStatus{code: 1, signal: None};
+
+    pub fn new(code: i32) -> ExitStatus {
+        ExitStatus{code, signal: None}
+    }
+
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// The signal that killed the command, if one did.
+    pub fn signal(&self) -> Option<i32> {
+        self.signal
+    }
+
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+}
+
+impl From<bool> for ExitStatus {
+    fn from(success: bool) -> ExitStatus {
+        if success { ExitStatus::SUCCESS } else { ExitStatus::FAILURE }
+    }
+}
+
+impl From<std::process::ExitStatus> for ExitStatus {
+    fn from(status: std::process::ExitStatus) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus{code: status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or_default()), signal: status.signal()}
+    }
 }
 
 pub struct CommandProcess {
     pub(crate) child: std::process::Child,
-    pub(crate) result: Option<bool>
+    pub(crate) result: Option<ExitStatus>
 }
 
 impl Process for CommandProcess {
-    fn get_stdout(self: Box<Self>) -> Stdio {
-        self.child.stdout.unwrap().into()
-    }
-
-    fn wait(&mut self) -> bool {
+    fn wait(&mut self) -> ExitStatus {
         match self.result {
             Some(result) => result,
             None => {
                 match self.child.wait() {
-                    Ok(result) => {
-                        self.result = Some(result.success());
-                        self.result.unwrap()
-                    }
+                    Ok(result) => *self.result.insert(result.into()),
                     Err(err) => {
                         eprintln!("{}", err);
-                        self.result = Some(false);
-                        false
+                        self.result = Some(ExitStatus::FAILURE);
+                        ExitStatus::FAILURE
                     }
                 }
             }
         }
     }
+
+    fn pid(&self) -> Option<libc::pid_t> {
+        Some(self.child.id() as libc::pid_t)
+    }
 }
 
-pub struct CDProcess {
-    pub(crate) target: Option<String>,
-    pub(crate) result: Option<bool>
+pub struct CompletedProcess {
+    pub(crate) result: ExitStatus
 }
 
-impl CDProcess {
-    fn expand(&self) -> PathBuf {
-        match &self.target {
-            Some(target) => expand(target),
-            None => PathBuf::from(dirs::home_dir().unwrap())
+impl Process for CompletedProcess {
+    fn wait(&mut self) -> ExitStatus {
+        self.result
+    }
+}
+
+/// A copy of the shell forked to run a pipeline stage.
+pub struct ForkedProcess {
+    pid: libc::pid_t,
+    result: Option<ExitStatus>
+}
+
+impl Process for ForkedProcess {
+    fn wait(&mut self) -> ExitStatus {
+        if let Some(result) = self.result {
+            return result;
         }
+        let mut status = 0;
+        let result = loop {
+            if unsafe { libc::waitpid(self.pid, &mut status, 0) } >= 0 {
+                use std::os::unix::process::ExitStatusExt;
+                break std::process::ExitStatus::from_raw(status).into();
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                eprintln!("{}", err);
+                break ExitStatus::FAILURE;
+            }
+        };
+        *self.result.insert(result)
+    }
+
+    fn pid(&self) -> Option<libc::pid_t> {
+        Some(self.pid)
     }
 }
 
-impl Process for CDProcess {
-    fn get_stdout(self: Box<Self>) -> Stdio {
-        Stdio::null()
+/// Runs `child` in a forked copy of the shell, which exits with the status it returns
+/// without running any destructors or exit handlers of the parent. The copy is given back
+/// the default dispositions of the signals the shell ignores.
+///
+/// Only the calling thread is copied, so a lock held by any other thread at the time of the
+/// fork is never released in the copy. The locks the shell itself takes, on its signal
+/// dispositions and on the standard streams, are held across the fork so that they are free
+/// on both sides; code run by `child` must not wait on any other lock or thread of the host.
+pub fn fork<F: FnOnce() -> ExitStatus>(child: F) -> io::Result<ForkedProcess> {
+    let held = (crate::signals::hold(), io::stdout().lock(), io::stderr().lock());
+    let pid = unsafe { libc::fork() };
+    drop(held);
+    match pid {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            crate::signals::restore();
+            let status = std::panic::catch_unwind(std::panic::AssertUnwindSafe(child)).unwrap_or(ExitStatus::FAILURE);
+            unsafe { libc::_exit(status.code()) }
+        }
+        pid => Ok(ForkedProcess{ pid, result: None })
     }
+}
+
+/// Returns the user and system CPU time used so far by the shell and by the children it
+/// has waited for.
+pub fn cpu_times() -> (Duration, Duration) {
+    let usage = |who| {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(who, &mut usage) };
+        usage
+    };
+    let duration = |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    let (own, children) = (usage(libc::RUSAGE_SELF), usage(libc::RUSAGE_CHILDREN));
+    (duration(own.ru_utime) + duration(children.ru_utime), duration(own.ru_stime) + duration(children.ru_stime))
+}
+
+/// Checks that `target` is a directory that may be entered. The process-wide working
+/// directory is never changed; the interpreter records the new directory itself.
+pub struct CDProcess {
+    pub(crate) target: PathBuf,
+    pub(crate) result: Option<ExitStatus>
+}
 
-    fn wait(&mut self) -> bool {
+impl Process for CDProcess {
+    fn wait(&mut self) -> ExitStatus {
         match self.result {
             Some(result) => result,
             None => {
-                match std::env::set_current_dir(self.expand()) {
-                    Ok(_) => {
-                        self.result = Some(true);
-                        true
-                    }
-                    Err(err) => {
-                        eprintln!("{}", err);
-                        self.result = Some(false);
-                        false
-                    }
-                }
+                let _ = self.enter();
+                self.result.unwrap_or(ExitStatus::FAILURE)
             }
         }
     }
 }
 
-pub fn expand<P: AsRef<Path>>(path: P) -> PathBuf {
-    let mut pb = PathBuf::new();
-    let home = OsStr::new("~");
-    path.as_ref().components().into_iter().for_each(|component| {
+impl CDProcess {
+    /// Performs the check, returning why the directory cannot be entered so that the caller
+    /// can report it.
+    pub fn enter(&mut self) -> io::Result<()> {
+        let checked = match std::fs::metadata(&self.target) {
+            Ok(metadata) if !metadata.is_dir() => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+            Ok(_) if !access(&self.target, libc::X_OK) => Err(io::Error::last_os_error()),
+            Ok(_) => Ok(()),
+            Err(err) => Err(err)
+        };
+        self.result = Some(checked.is_ok().into());
+        checked
+    }
+}
+
+/// Resolves `path` relative to the interpreter's working directory `cwd`. An empty path is
+/// left empty so that it still fails to name any file.
+pub fn resolve<C: AsRef<Path>, P: AsRef<Path>>(cwd: C, path: P) -> PathBuf {
+    let path = path.as_ref();
+    if path.as_os_str().is_empty() {
+        PathBuf::new()
+    } else {
+        cwd.as_ref().join(path)
+    }
+}
+
+/// Returns whether the current user may access `path` with `mode`, as by `access(2)`.
+pub fn access(path: &Path, mode: libc::c_int) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false
+    }
+}
+
+/// Resolves `target` against `pwd` lexically, as `cd -L` does: `.` components are dropped
+/// and `..` removes the preceding component rather than following symlinks.
+pub fn logical<P: AsRef<Path>, T: AsRef<Path>>(pwd: P, target: T) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in pwd.as_ref().join(target).components() {
         match component {
-            Component::Normal(path) if path == home => pb.push(dirs::home_dir().unwrap()),
-            path => pb.push(path)
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component)
+        }
+    }
+    resolved
+}
+
+/// Returns whether both paths refer to the same file.
+pub fn same_file<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> bool {
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false
+    }
+}
+
+/// Returns the system's message for `err`, as `strerror(3)` gives it, without the error
+/// number that the `Display` of `io::Error` adds.
+pub fn strerror(err: &io::Error) -> String {
+    let Some(code) = err.raw_os_error() else { return err.to_string() };
+    let mut buf: [libc::c_char; 256] = [0; 256];
+    if unsafe { libc::strerror_r(code, buf.as_mut_ptr(), buf.len()) } != 0 {
+        return err.to_string();
+    }
+    unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+/// Looks up the home directory of `user` in the passwd database.
+pub fn user_home(user: &str) -> Option<PathBuf> {
+    let name = CString::new(user).ok()?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let rc = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if rc != libc::ERANGE {
+            break;
         }
-    });
-    pb
-}
\ No newline at end of file
+        buf.resize(buf.len() * 2, 0);
+    }
+    if result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+    let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logical() {
+        assert_eq!(logical("/a/b", "c/./d"), PathBuf::from("/a/b/c/d"));
+        assert_eq!(logical("/a/b", "../c"), PathBuf::from("/a/c"));
+        assert_eq!(logical("/a/b", "/x/../../y/"), PathBuf::from("/y"));
+        assert_eq!(logical("/", ".."), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("/a", "b/c"), PathBuf::from("/a/b/c"));
+        assert_eq!(resolve("/a", "/b"), PathBuf::from("/b"));
+        assert_eq!(resolve("/a", ""), PathBuf::new());
+    }
+
+    #[test]
+    fn test_strerror() {
+        assert_eq!(strerror(&io::Error::from_raw_os_error(libc::ENOENT)), "No such file or directory");
+        assert_eq!(strerror(&io::Error::other("no code")), "no code");
+    }
+
+    #[test]
+    fn test_user_home() {
+        // Compare against the entry for the current user, since no home directory is the
+        // same on every system.
+        let passwd = unsafe { libc::getpwuid(libc::getuid()) };
+        assert!(!passwd.is_null());
+        let (name, dir) = unsafe { (CStr::from_ptr((*passwd).pw_name), CStr::from_ptr((*passwd).pw_dir)) };
+        let home = PathBuf::from(OsStr::from_bytes(dir.to_bytes()));
+        assert_eq!(user_home(name.to_str().unwrap()), Some(home));
+        assert_eq!(user_home("no_such_user_rsh"), None);
+    }
+}
@@ -0,0 +1,237 @@
+use libc::c_int;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// The signals the shell knows by name, in the order of their numbers.
+const SIGNALS: &[(&str, c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS)
+];
+
+/// The signals an interactive shell ignores, so that the keys which interrupt, quit or stop
+/// a job at the terminal leave the shell itself alone.
+pub const INTERACTIVE: [c_int; 5] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+/// The signals a shell ignores while it waits for a foreground job, which alone is killed
+/// when they are sent to the whole process group.
+pub const FOREGROUND: [c_int; 2] = [libc::SIGINT, libc::SIGQUIT];
+
+/// A bit for each signal the shell has chosen to ignore, read by children between fork
+/// and exec.
+static IGNORED: AtomicU64 = AtomicU64::new(0);
+
+/// A bit for each signal the shell catches to run a trap.
+static CAUGHT: AtomicU64 = AtomicU64::new(0);
+
+/// A bit for each caught signal that has arrived but whose trap has not yet run.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn caught(signal: c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// What the shell does when a signal arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    Default,
+    Ignore,
+    /// Records the signal for its trap to run at the next safe point.
+    Catch
+}
+
+/// Sets the disposition of `signal`. While a guard from `ignore` holds the signal, the new
+/// disposition takes effect once the last guard is dropped.
+pub fn set(signal: c_int, disposition: Disposition) -> io::Result<()> {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = match disposition {
+        Disposition::Default => libc::SIG_DFL,
+        Disposition::Ignore => libc::SIG_IGN,
+        Disposition::Catch => caught as extern "C" fn(c_int) as libc::sighandler_t
+    };
+    action.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    let mut saved = SAVED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(entry) = saved.iter_mut().find(|entry| entry.0 == signal) {
+        entry.1 = action;
+    } else if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match disposition {
+        Disposition::Catch => CAUGHT.fetch_or(1 << signal, Ordering::SeqCst),
+        _ => CAUGHT.fetch_and(!(1 << signal), Ordering::SeqCst)
+    };
+    Ok(())
+}
+
+/// Records `signal` as having arrived, as when the shell learns that a foreground job was
+/// killed by it.
+pub fn mark(signal: c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// Takes the caught signals that have arrived since the last call, in order of number.
+pub fn pending() -> Vec<c_int> {
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    SIGNALS.iter().map(|&(_, signal)| signal).filter(|signal| pending & (1 << signal) != 0).collect()
+}
+
+/// The disposition each ignored signal had before, with the number of `Ignored` guards
+/// holding it.
+static SAVED: Mutex<Vec<(c_int, libc::sigaction, usize)>> = Mutex::new(vec![]);
+
+/// Locks the saved dispositions until the guard is dropped, so that a process forked while it
+/// is held does not inherit the lock taken by another thread.
+pub(crate) fn hold() -> MutexGuard<'static, Vec<(c_int, libc::sigaction, usize)>> {
+    SAVED.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Ignores signals for as long as it is held. Guards may overlap; the previous disposition
+/// is restored when the last guard for a signal is dropped.
+pub struct Ignored {
+    signals: Vec<c_int>
+}
+
+/// Ignores `signals` in the shell until the returned guard is dropped. A signal that was
+/// already ignored when the shell started is left alone, and children go on ignoring it.
+/// Signals caught for a trap go on being caught.
+pub fn ignore(signals: &[c_int]) -> Ignored {
+    let mut saved = SAVED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut held = vec![];
+    for &signal in signals {
+        if CAUGHT.load(Ordering::SeqCst) & (1 << signal) != 0 {
+            continue;
+        }
+        if let Some(entry) = saved.iter_mut().find(|entry| entry.0 == signal) {
+            entry.2 += 1;
+            held.push(signal);
+            continue;
+        }
+        let mut ignore: libc::sigaction = unsafe { std::mem::zeroed() };
+        ignore.sa_sigaction = libc::SIG_IGN;
+        let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sigaction(signal, &ignore, &mut previous) } != 0 {
+            continue;
+        }
+        if previous.sa_sigaction == libc::SIG_IGN {
+            continue;
+        }
+        saved.push((signal, previous, 1));
+        IGNORED.fetch_or(1 << signal, Ordering::SeqCst);
+        held.push(signal);
+    }
+    Ignored{ signals: held }
+}
+
+impl Drop for Ignored {
+    fn drop(&mut self) {
+        let mut saved = SAVED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for signal in &self.signals {
+            let Some(idx) = saved.iter().position(|entry| entry.0 == *signal) else { continue };
+            saved[idx].2 -= 1;
+            if saved[idx].2 == 0 {
+                let (signal, previous, _) = saved.remove(idx);
+                IGNORED.fetch_and(!(1 << signal), Ordering::SeqCst);
+                unsafe { libc::sigaction(signal, &previous, std::ptr::null_mut()) };
+            }
+        }
+    }
+}
+
+/// Gives a child the default disposition of every signal the shell is ignoring or catching.
+/// Only async-signal-safe calls are made, so that it can run between fork and exec.
+pub fn restore() {
+    let ignored = IGNORED.load(Ordering::SeqCst) | CAUGHT.load(Ordering::SeqCst);
+    for (_, signal) in SIGNALS {
+        if ignored & (1 << signal) != 0 {
+            unsafe { libc::signal(*signal, libc::SIG_DFL) };
+        }
+    }
+}
+
+/// Whether the shell is ignoring or catching any signals that a forked child should not.
+pub fn ignoring() -> bool {
+    IGNORED.load(Ordering::SeqCst) | CAUGHT.load(Ordering::SeqCst) != 0
+}
+
+/// Every signal's name, without the `SIG` prefix, with its number.
+pub fn list() -> &'static [(&'static str, c_int)] {
+    SIGNALS
+}
+
+/// Parses a signal given by number or by name, with or without the `SIG` prefix and in
+/// any case.
+pub fn number(spec: &str) -> Option<c_int> {
+    if let Ok(number) = spec.parse::<c_int>() {
+        // Signal 0 only checks that a process exists.
+        return (number == 0 || name(number).is_some()).then_some(number);
+    }
+    let spec = spec.to_ascii_uppercase();
+    let spec = spec.strip_prefix("SIG").unwrap_or(&spec);
+    SIGNALS.iter().find(|(name, _)| *name == spec).map(|&(_, number)| number)
+}
+
+/// Returns the name of `signal`, without the `SIG` prefix.
+pub fn name(signal: c_int) -> Option<&'static str> {
+    SIGNALS.iter().find(|(_, number)| *number == signal).map(|&(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number() {
+        assert_eq!(number("TERM"), Some(libc::SIGTERM));
+        assert_eq!(number("sigint"), Some(libc::SIGINT));
+        assert_eq!(number("9"), Some(libc::SIGKILL));
+        assert_eq!(number("0"), Some(0));
+        assert_eq!(number("NOPE"), None);
+        assert_eq!(number("999"), None);
+        assert_eq!(name(libc::SIGTSTP), Some("TSTP"));
+    }
+
+    #[test]
+    fn test_ignore() {
+        let disposition = || {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            unsafe { libc::sigaction(libc::SIGUSR2, std::ptr::null(), &mut action) };
+            action.sa_sigaction
+        };
+        let outer = ignore(&[libc::SIGUSR2]);
+        let inner = ignore(&[libc::SIGUSR2]);
+        assert_eq!(disposition(), libc::SIG_IGN);
+        assert!(ignoring());
+        drop(outer);
+        assert_eq!(disposition(), libc::SIG_IGN);
+        drop(inner);
+        assert_eq!(disposition(), libc::SIG_DFL);
+    }
+}
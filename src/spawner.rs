@@ -0,0 +1,291 @@
+use crate::{jobs, signals};
+use crate::physical::{CommandProcess, Process, resolve};
+use crate::{Environment, ExitStatus};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// Starts the external commands run by an interpreter. `ProcessSpawner` is used unless
+/// another spawner is given to `InterpreterBuilder::spawner`.
+pub trait Spawner {
+    fn spawn(&mut self, request: SpawnRequest) -> io::Result<Box<dyn Process>>;
+
+    /// Replaces the interpreting process with the command, for `exec`, returning only if it
+    /// cannot be run. By default the command is spawned instead, and the interpreter exits
+    /// with its status once it finishes.
+    fn exec(&mut self, request: SpawnRequest) -> io::Result<Box<dyn Process>> {
+        self.spawn(request)
+    }
+
+    /// Sends `signal` to the process `pid`, or to a process group if `pid` is negative, for
+    /// `kill`. By default the signal is sent with `kill(2)`.
+    fn kill(&mut self, pid: libc::pid_t, signal: libc::c_int) -> io::Result<()> {
+        match unsafe { libc::kill(pid, signal) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Everything needed to start one command.
+#[derive(Debug)]
+pub struct SpawnRequest {
+    /// The expanded command line, starting with the program as it was written.
+    pub argv: Vec<String>,
+    /// The complete environment of the command.
+    pub env: Environment,
+    pub cwd: PathBuf,
+    pub stdin: Stream,
+    pub stdout: Stream,
+    pub stderr: Stream,
+    /// The process group to start the command in when the interpreter has job control.
+    /// Otherwise the command stays in the interpreter's own group.
+    pub process_group: Option<ProcessGroup>,
+    /// Descriptors opened with `exec`, as pairs of the number the command sees and the
+    /// interpreter's descriptor. They stay open for as long as the request is handled.
+    pub fds: Vec<(RawFd, RawFd)>
+}
+
+/// The process group of a job that a command joins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProcessGroup {
+    /// The group to join, or 0 to start a new group led by the command.
+    pub pgid: libc::pid_t,
+    /// The terminal to hand to the group, for a job run in the foreground.
+    pub terminal: Option<RawFd>
+}
+
+/// Where one of a command's standard streams is connected.
+#[derive(Debug)]
+pub enum Stream {
+    /// The interpreting process's own stream.
+    Inherit,
+    /// An open file, pipe or other descriptor.
+    Fd(OwnedFd)
+}
+
+/// The connection of a stream, without the descriptor itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Inherit,
+    Fd
+}
+
+impl Stream {
+    pub fn kind(&self) -> StreamKind {
+        match self {
+            Stream::Inherit => StreamKind::Inherit,
+            Stream::Fd(_) => StreamKind::Fd
+        }
+    }
+
+    fn into_stdio(self) -> Option<Stdio> {
+        match self {
+            Stream::Inherit => None,
+            Stream::Fd(fd) => Some(fd.into())
+        }
+    }
+}
+
+/// Spawns real child processes.
+#[derive(Default)]
+pub struct ProcessSpawner;
+
+impl ProcessSpawner {
+    fn command(request: SpawnRequest) -> std::process::Command {
+        let mut argv = request.argv.into_iter();
+        let program = argv.next().unwrap_or_default();
+        // Programs named by a relative path are found relative to the interpreter's directory.
+        let mut command = match program.contains('/') {
+            true => std::process::Command::new(resolve(&request.cwd, program)),
+            false => std::process::Command::new(program)
+        };
+        command.args(argv).env_clear().envs(request.env).current_dir(request.cwd);
+        if let Some(stdin) = request.stdin.into_stdio() {
+            command.stdin(stdin);
+        }
+        if let Some(stdout) = request.stdout.into_stdio() {
+            command.stdout(stdout);
+        }
+        if let Some(stderr) = request.stderr.into_stdio() {
+            command.stderr(stderr);
+        }
+        let (group, fds) = (request.process_group, request.fds);
+        if group.is_some() || !fds.is_empty() || signals::ignoring() {
+            // SAFETY: `enter`, `restore` and `inherit` only make async-signal-safe calls.
+            unsafe {
+                command.pre_exec(move || {
+                    match &group {
+                        Some(group) => jobs::enter(group)?,
+                        None => signals::restore()
+                    }
+                    inherit(&fds)
+                })
+            };
+        }
+        command
+    }
+}
+
+impl Spawner for ProcessSpawner {
+    fn spawn(&mut self, request: SpawnRequest) -> io::Result<Box<dyn Process>> {
+        Ok(Box::new(CommandProcess{ child: Self::command(request).spawn()?, result: None }))
+    }
+
+    fn exec(&mut self, request: SpawnRequest) -> io::Result<Box<dyn Process>> {
+        Err(Self::command(request).exec())
+    }
+}
+
+/// Gives a child the descriptors opened with `exec` at the numbers it expects them, between
+/// fork and exec. Descriptors that are already in place only need to survive the exec.
+fn inherit(fds: &[(RawFd, RawFd)]) -> io::Result<()> {
+    for &(target, fd) in fds {
+        let result = match target == fd {
+            true => unsafe { libc::fcntl(fd, libc::F_SETFD, 0) },
+            false => unsafe { libc::dup2(fd, target) }
+        };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// What a mocked command writes and the status it exits with.
+#[derive(Clone, Debug, Default)]
+pub struct Scripted {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub status: ExitStatus
+}
+
+/// A command started through a `MockSpawner`.
+#[derive(Clone, Debug)]
+pub struct SpawnRecord {
+    pub argv: Vec<String>,
+    pub env: Environment,
+    pub cwd: PathBuf,
+    /// Everything the command was given on stdin, unless stdin was inherited.
+    pub stdin: Option<Vec<u8>>,
+    pub stdout: StreamKind,
+    pub stderr: StreamKind,
+    /// The numbers of the descriptors opened with `exec` that the command was given.
+    pub fds: Vec<RawFd>
+}
+
+#[derive(Default)]
+struct MockState {
+    scripts: HashMap<String, VecDeque<Scripted>>,
+    records: Vec<SpawnRecord>,
+    signals: Vec<(libc::pid_t, libc::c_int)>
+}
+
+/// A spawner that runs nothing and signals nothing. Each command is recorded and answered
+/// with the output scripted for its program; programs without a script are not found. Clones
+/// share their scripts and records, so a clone can be kept to inspect an interpreter's
+/// spawner.
+#[derive(Clone, Default)]
+pub struct MockSpawner {
+    state: Arc<Mutex<MockState>>
+}
+
+impl MockSpawner {
+    pub fn new() -> MockSpawner {
+        MockSpawner::default()
+    }
+
+    /// Queues the response to the next run of `program`. The last queued response is
+    /// repeated for any later runs.
+    pub fn script<S: ToString>(&self, program: S, scripted: Scripted) {
+        self.lock().scripts.entry(program.to_string()).or_default().push_back(scripted);
+    }
+
+    /// Returns the commands spawned so far, in order.
+    pub fn records(&self) -> Vec<SpawnRecord> {
+        self.lock().records.clone()
+    }
+
+    /// Returns the signals `kill` has sent so far, in order, with the process or group each
+    /// was sent to. None of them is delivered.
+    pub fn signals(&self) -> Vec<(libc::pid_t, libc::c_int)> {
+        self.lock().signals.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        // A panic while the lock was held cannot leave the state half updated.
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Spawner for MockSpawner {
+    fn spawn(&mut self, request: SpawnRequest) -> io::Result<Box<dyn Process>> {
+        let stdin = match request.stdin {
+            Stream::Fd(fd) => {
+                let mut input = vec![];
+                File::from(fd).read_to_end(&mut input)?;
+                Some(input)
+            }
+            _ => None
+        };
+        let mut state = self.lock();
+        state.records.push(SpawnRecord{
+            argv: request.argv.clone(),
+            env: request.env,
+            cwd: request.cwd,
+            stdin,
+            stdout: request.stdout.kind(),
+            stderr: request.stderr.kind(),
+            fds: request.fds.iter().map(|&(target, _)| target).collect()
+        });
+        let program = request.argv.first().cloned().unwrap_or_default();
+        let scripted = match state.scripts.get_mut(&program) {
+            Some(queue) if queue.len() > 1 => queue.pop_front(),
+            Some(queue) => queue.front().cloned(),
+            None => None
+        };
+        drop(state);
+        let scripted = scripted.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: command not found", program)))?;
+        let (stdout, stderr, result) = (request.stdout, request.stderr, scripted.status);
+        let output = std::thread::spawn(move || {
+            write(stderr, &scripted.stderr, io::stderr())?;
+            write(stdout, &scripted.stdout, io::stdout())
+        });
+        Ok(Box::new(ScriptedProcess{ result, output: Some(output) }))
+    }
+
+    fn kill(&mut self, pid: libc::pid_t, signal: libc::c_int) -> io::Result<()> {
+        self.lock().signals.push((pid, signal));
+        Ok(())
+    }
+}
+
+/// A command answered by a `MockSpawner`. Its output is written from a thread, so that it
+/// cannot fill a pipe before the command reading it has started.
+struct ScriptedProcess {
+    result: ExitStatus,
+    output: Option<JoinHandle<io::Result<()>>>
+}
+
+impl Process for ScriptedProcess {
+    fn wait(&mut self) -> ExitStatus {
+        if let Some(output) = self.output.take() {
+            // As for a real command, output that cannot be written leaves the status alone.
+            let _ = output.join();
+        }
+        self.result
+    }
+}
+
+fn write<W: Write>(stream: Stream, bytes: &[u8], mut inherited: W) -> io::Result<()> {
+    match stream {
+        Stream::Fd(fd) => File::from(fd).write_all(bytes),
+        _ => inherited.write_all(bytes)
+    }
+}
@@ -1,4 +1,6 @@
 use crate::Environment;
+use crate::arithmetic;
+use crate::physical;
 use std::iter::Peekable;
 use crate::errors::{InterpreterResult, InterpreterError};
 
@@ -6,64 +8,549 @@ const ESCAPE: char = '\\';
 const SUBSTITUTION: char = '$';
 const OPEN: char = '{';
 const CLOSE: char = '}';
+const SINGLE_QUOTE: char = '\'';
+const DOUBLE_QUOTE: char = '"';
+const OPEN_PAREN: char = '(';
+const CLOSE_PAREN: char = ')';
 
-pub fn substitution<S: AsRef<str>>(s: S, env: &Environment) -> InterpreterResult<String> {
-    let mut sub = String::with_capacity(s.as_ref().len());
-    let mut chars = s.as_ref().chars().peekable();
+/// Special parameters that are a single character long.
+const SPECIAL: &[char] = &['@', '*', '#', '$', '-', '?'];
+
+/// Characters that keep their special meaning after a backslash inside double quotes.
+const QUOTED_ESCAPES: &[char] = &[SUBSTITUTION, '`', DOUBLE_QUOTE, ESCAPE, '\n'];
+
+/// How deeply arithmetic expansions may nest within each other.
+const MAX_NESTING: usize = 64;
+
+/// The field separators used when `IFS` is unset.
+const DEFAULT_IFS: &str = " \t\n";
+
+/// The state of the shell, besides its variables, that expansions depend on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Context {
+    /// Whether expanding a parameter that is not set is an error, as under `set -u`.
+    pub nounset: bool,
+    /// The status of the last pipeline, which `$?` expands to.
+    pub status: i32
+}
+
+pub fn substitution<S: AsRef<str>>(s: S, env: &mut Environment, context: Context) -> InterpreterResult<String> {
+    Ok(fragments(s, env, context)?.into_iter().map(|fragment| fragment.text).collect())
+}
+
+/// Like `substitution`, but passes text that was quoted in the original word through
+/// `escape`. This lets quoted characters stay literal when the result is used as a pattern.
+pub fn escaped_substitution<S: AsRef<str>, F: Fn(&str) -> String>(s: S, env: &mut Environment, context: Context, escape: F) -> InterpreterResult<String> {
+    Ok(fragments(s, env, context)?.into_iter().map(|fragment| {
+        if fragment.quoting == Quoting::Quoted { escape(&fragment.text) } else { fragment.text }
+    }).collect())
+}
+
+/// Expands a word into zero or more fields, performing tilde expansion and splitting the
+/// unquoted results of expansions on the characters of `IFS`.
+pub fn fields<S: AsRef<str>>(s: S, env: &mut Environment, context: Context) -> InterpreterResult<Vec<String>> {
+    let fragments = fragments(tilde(s, false, env), env, context)?;
+    let ifs = env.get("IFS").map(String::as_str).unwrap_or(DEFAULT_IFS);
+    let mut splitter = FieldSplitter{ifs, ..FieldSplitter::default()};
+    for fragment in fragments {
+        match fragment.quoting {
+            Quoting::Literal | Quoting::Quoted => splitter.push(&fragment.text),
+            Quoting::Expanded => splitter.split(&fragment.text),
+            Quoting::Break => splitter.finish(true),
+            Quoting::Nothing => splitter.nothing = true
+        }
+    }
+    splitter.finish(false);
+    Ok(splitter.fields)
+}
+
+#[derive(Default)]
+struct FieldSplitter<'a> {
+    ifs: &'a str,
+    fields: Vec<String>,
+    field: String,
+    /// Whether the current field exists, even if it is empty (e.g. `""`).
+    started: bool,
+    /// Whether the previous field was ended by IFS whitespace, which absorbs a following
+    /// non-whitespace separator.
+    delimited: bool,
+    /// Whether the current field contained a `"$@"` without positional parameters.
+    nothing: bool
+}
+
+impl FieldSplitter<'_> {
+    fn push(&mut self, text: &str) {
+        self.field.push_str(text);
+        self.started = true;
+        self.delimited = false;
+    }
+
+    fn split(&mut self, text: &str) {
+        for c in text.chars() {
+            if !self.ifs.contains(c) {
+                self.push(c.encode_utf8(&mut [0; 4]));
+            } else if c.is_whitespace() {
+                if self.started {
+                    self.finish(false);
+                    self.delimited = true;
+                }
+            } else {
+                if self.started || !self.delimited {
+                    self.finish(true);
+                }
+                self.delimited = false;
+            }
+        }
+    }
+
+    fn finish(&mut self, force: bool) {
+        let vanished = self.nothing && self.field.is_empty();
+        if (force || self.started) && !vanished {
+            self.fields.push(std::mem::take(&mut self.field));
+        }
+        self.field.clear();
+        self.started = false;
+        self.nothing = false;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Quoting {
+    /// Text written unquoted in the word itself.
+    Literal,
+    /// Text that was quoted or escaped.
+    Quoted,
+    /// The unquoted result of an expansion, which is subject to field splitting.
+    Expanded,
+    /// Separates the fields produced by `"$@"`.
+    Break,
+    /// Produced by `"$@"` without any positional parameters, which expands to no field.
+    Nothing,
+}
+
+/// A run of expanded text that was quoted in the same way.
+struct Fragment {
+    text: String,
+    quoting: Quoting
+}
+
+#[derive(Default)]
+struct Fragments(Vec<Fragment>);
+
+impl Fragments {
+    fn push<S: AsRef<str>>(&mut self, text: S, quoting: Quoting) {
+        match self.0.last_mut() {
+            Some(last) if last.quoting == quoting && quoting != Quoting::Break => last.text.push_str(text.as_ref()),
+            _ => self.0.push(Fragment{text: text.as_ref().to_string(), quoting})
+        }
+    }
+
+    fn push_char(&mut self, c: char, quoting: Quoting) {
+        self.push(c.encode_utf8(&mut [0; 4]), quoting)
+    }
+
+    /// Pushes `"$@"`: each positional parameter becomes a separate field.
+    fn push_positional(&mut self, env: &Environment) {
+        let params = positional(env);
+        if params.is_empty() {
+            self.push("", Quoting::Nothing);
+        }
+        for (idx, param) in params.iter().enumerate() {
+            if idx > 0 {
+                self.push(" ", Quoting::Break);
+            }
+            self.push(param, Quoting::Quoted);
+        }
+    }
+}
+
+fn fragments<S: AsRef<str>>(s: S, env: &mut Environment, context: Context) -> InterpreterResult<Vec<Fragment>> {
+    fragments_at(s.as_ref(), env, context, 0)
+}
+
+/// Expands `s`, which is nested inside `depth` arithmetic expansions.
+fn fragments_at(s: &str, env: &mut Environment, context: Context, depth: usize) -> InterpreterResult<Vec<Fragment>> {
+    let mut sub = Fragments::default();
+    let mut chars = s.chars().peekable();
+    let mut quoted = false;
     loop {
+        let (literal, expanded) = if quoted { (Quoting::Quoted, Quoting::Quoted) } else { (Quoting::Literal, Quoting::Expanded) };
         match chars.next() {
-            Some(SUBSTITUTION) => {
-                if sub.ends_with(ESCAPE) {
-                    sub.pop();
-                    sub.push(SUBSTITUTION);
-                    continue;
+            Some(ESCAPE) => match chars.next() {
+                Some(c) if !quoted || QUOTED_ESCAPES.contains(&c) => sub.push_char(c, Quoting::Quoted),
+                Some(c) => {
+                    sub.push_char(ESCAPE, Quoting::Quoted);
+                    sub.push_char(c, Quoting::Quoted);
+                }
+                None => sub.push_char(ESCAPE, literal)
+            },
+            Some(SINGLE_QUOTE) if !quoted => {
+                // An empty pair of quotes still produces a (quoted) empty fragment.
+                sub.push("", Quoting::Quoted);
+                loop {
+                    match chars.next() {
+                        Some(SINGLE_QUOTE) | None => break,
+                        Some(c) => sub.push_char(c, Quoting::Quoted)
+                    }
                 }
+            },
+            Some(DOUBLE_QUOTE) => {
+                quoted = !quoted;
+                sub.push("", Quoting::Quoted);
+            },
+            Some(SUBSTITUTION) => {
                 match chars.peek() {
-                    Some(space) if space.is_whitespace() => sub.push(SUBSTITUTION),
-                    None => sub.push(SUBSTITUTION),
                     Some(&OPEN) => {
                         chars.next();
-                        sub.push_str(delimited(&mut chars, env)?.as_str())
+                        sub.push(delimited(&mut chars, env, context, depth)?, expanded)
+                    }
+                    Some(&OPEN_PAREN) => {
+                        chars.next();
+                        sub.push(parenthesized(&mut chars, env, context, depth)?, expanded)
                     }
-                    Some(_) => sub.push_str(longest_match(&mut chars, env).as_str()),
+                    Some('@') if quoted => {
+                        chars.next();
+                        sub.push_positional(env)
+                    }
+                    Some('*') if quoted => {
+                        chars.next();
+                        let separator = match env.get("IFS") {
+                            Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+                            None => " ".to_string()
+                        };
+                        sub.push(positional(env).join(&separator), expanded)
+                    }
+                    Some(c) if is_name_start(*c) || c.is_ascii_digit() || SPECIAL.contains(c) => sub.push(longest_match(&mut chars, env, context)?, expanded),
+                    _ => sub.push_char(SUBSTITUTION, literal),
                 }
             },
-            Some(other) => sub.push(other),
-            None => return Ok(sub)
+            Some(other) => sub.push_char(other, literal),
+            None => return Ok(sub.0)
         }
     }
 }
 
-fn delimited<T: Iterator<Item=char>>(stream: &mut Peekable<T>, env: &Environment) -> InterpreterResult<String> {
-    let mut varname = String::new();
+/// Performs tilde expansion on a raw word: `~` and `~user` become home directories, `~+`
+/// the current and `~-` the previous directory. In assignments a tilde prefix may also
+/// follow any unquoted `:`. Expansions are quoted so that they are not expanded further.
+pub fn tilde<S: AsRef<str>>(word: S, assignment: bool, env: &Environment) -> String {
+    let word = word.as_ref();
+    let mut expanded = String::with_capacity(word.len());
+    for (idx, segment) in segments(word, assignment).into_iter().enumerate() {
+        if idx > 0 {
+            expanded.push(':');
+        }
+        expanded.push_str(&tilde_prefix(segment, assignment, env));
+    }
+    expanded
+}
+
+/// Splits a word on unquoted colons, if it is an assignment value.
+fn segments(word: &str, assignment: bool) -> Vec<&str> {
+    if !assignment {
+        return vec![word];
+    }
+    let mut segments = vec![];
+    let (mut start, mut single, mut double, mut escaped) = (0, false, false, false);
+    for (idx, c) in word.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            ESCAPE if !single => escaped = true,
+            SINGLE_QUOTE if !double => single = !single,
+            DOUBLE_QUOTE if !single => double = !double,
+            ':' if !single && !double => {
+                segments.push(&word[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&word[start..]);
+    segments
+}
+
+fn tilde_prefix(segment: &str, assignment: bool, env: &Environment) -> String {
+    if !segment.starts_with('~') {
+        return segment.to_string();
+    }
+    let end = segment.find(|c| c == '/' || (assignment && c == ':')).unwrap_or(segment.len());
+    let prefix = &segment[1..end];
+    if prefix.contains([ESCAPE, SINGLE_QUOTE, DOUBLE_QUOTE, SUBSTITUTION]) {
+        return segment.to_string();
+    }
+    let directory = match prefix {
+        "" => env.get("HOME").cloned().or_else(|| dirs::home_dir().map(|home| home.to_string_lossy().to_string())),
+        "+" => env.get("PWD").cloned(),
+        "-" => env.get("OLDPWD").cloned(),
+        user => physical::user_home(user).map(|home| home.to_string_lossy().to_string())
+    };
+    match directory {
+        Some(directory) => format!("{}{}", quote(&directory), &segment[end..]),
+        None => segment.to_string()
+    }
+}
+
+/// Single quotes `s` so that it survives expansion unchanged.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Expands the body of a `${...}` following the opening brace: a parameter, the length of
+/// one as `${#name}`, or one with a default, assigned, alternative or required value, as
+/// `${name:-word}`, `${name:=word}`, `${name:+word}` or `${name:?word}`. Without the colon
+/// only an unset parameter counts as missing, rather than an empty one as well.
+fn delimited<T: Iterator<Item=char>>(stream: &mut Peekable<T>, env: &mut Environment, context: Context, nesting: usize) -> InterpreterResult<String> {
+    let body = braced(stream)?;
+    let bad = || InterpreterError::bad_substitution(format!("${{{}}}: bad substitution", body));
+    if let Some(name) = body.strip_prefix('#').filter(|name| !name.is_empty()) {
+        if parameter_length(name) != name.len() {
+            return Err(bad());
+        }
+        return match name {
+            "@" | "*" => Ok(positional(env).len().to_string()),
+            _ => Ok(resolve(name.to_string(), env, context)?.chars().count().to_string())
+        };
+    }
+    let (name, rest) = body.split_at(parameter_length(&body));
+    if name.is_empty() {
+        return Err(bad());
+    }
+    let (colon, rest) = match rest.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, rest)
+    };
+    let mut chars = rest.chars();
+    let (operator, word) = match chars.next() {
+        None if !colon => return resolve(name.to_string(), env, context),
+        Some(operator @ ('-' | '=' | '+' | '?')) => (operator, chars.as_str()),
+        _ => return Err(bad())
+    };
+    let value = lookup(name, env, context).filter(|value| !colon || !value.is_empty());
+    let expand = |env: &mut Environment| match nesting {
+        MAX_NESTING.. => Err(InterpreterError::bad_substitution("parameter expansions nested too deeply")),
+        _ => Ok(fragments_at(&tilde(word, false, env), env, context, nesting + 1)?.into_iter().map(|fragment| fragment.text).collect::<String>())
+    };
+    match (operator, value) {
+        ('+', Some(_)) => expand(env),
+        ('+', None) => Ok(String::new()),
+        (_, Some(value)) => Ok(value),
+        ('-', None) => expand(env),
+        ('=', None) if is_variable_name(name) => {
+            let value = expand(env)?;
+            env.insert(name.to_string(), value.clone());
+            Ok(value)
+        }
+        ('=', None) => Err(InterpreterError::bad_substitution(format!("${}: cannot assign in this way", name))),
+        (_, None) => {
+            let message = expand(env)?;
+            let message = if message.is_empty() { "parameter null or not set".to_string() } else { message };
+            Err(InterpreterError::runtime(format!("{}: {}", name, message)))
+        }
+    }
+}
+
+/// Reads the body of a `${...}` up to its closing brace, which may contain quotes and other
+/// braced expansions.
+fn braced<T: Iterator<Item=char>>(stream: &mut Peekable<T>) -> InterpreterResult<String> {
+    let mut body = String::new();
+    let (mut depth, mut quoted) = (0, false);
     loop {
-        match stream.peek() {
-            None => return Err(InterpreterError{message:"unclosed delimiter".to_string()}),
-            Some(c) if c.is_whitespace() => return Err(InterpreterError{message:"bad substitution".to_string()}),
-            Some(&CLOSE) => {
-                stream.next();
-                break;
-            },
-            Some(_) => varname.push(stream.next().unwrap())
+        let c = stream.next().ok_or_else(|| InterpreterError::bad_substitution("unclosed delimiter"))?;
+        match c {
+            CLOSE if depth == 0 && !quoted => return Ok(body),
+            CLOSE if !quoted => depth -= 1,
+            OPEN if !quoted && body.ends_with(SUBSTITUTION) => depth += 1,
+            DOUBLE_QUOTE => quoted = !quoted,
+            SINGLE_QUOTE if !quoted => {
+                body.push(c);
+                while let Some(c) = stream.next_if(|&c| c != SINGLE_QUOTE) {
+                    body.push(c);
+                }
+                match stream.next() {
+                    Some(c) => body.push(c),
+                    None => return Err(InterpreterError::bad_substitution("unclosed delimiter"))
+                }
+                continue;
+            }
+            ESCAPE => {
+                body.push(c);
+                match stream.next() {
+                    Some(c) => body.push(c),
+                    None => return Err(InterpreterError::bad_substitution("unclosed delimiter"))
+                }
+                continue;
+            }
+            _ => {}
         }
+        body.push(c);
+    }
+}
+
+/// The length of the parameter that `body` starts with: a special parameter, a positional
+/// one, or a name that may be followed by an array index.
+fn parameter_length(body: &str) -> usize {
+    let mut chars = body.char_indices().peekable();
+    let end = match chars.next() {
+        Some((_, c)) if SPECIAL.contains(&c) => return c.len_utf8(),
+        Some((_, c)) if c.is_ascii_digit() => body.find(|c: char| !c.is_ascii_digit()).unwrap_or(body.len()),
+        Some((_, c)) if is_name_start(c) => body.find(|c: char| !is_name(c)).unwrap_or(body.len()),
+        _ => return 0
+    };
+    match body[end..].starts_with('[') {
+        true => body[end..].find(']').map(|close| end + close + 1).unwrap_or(end),
+        false => end
     }
-    Ok(resolve(varname, env))
 }
 
-fn longest_match<T: Iterator<Item=char>>(stream: &mut Peekable<T>, env: &Environment) -> String {
+fn longest_match<T: Iterator<Item=char>>(stream: &mut Peekable<T>, env: &Environment, context: Context) -> InterpreterResult<String> {
     let mut varname = String::new();
+    if let Some(&c) = stream.peek() {
+        if c.is_ascii_digit() || SPECIAL.contains(&c) {
+            stream.next();
+            varname.push(c);
+            return resolve(varname, env, context);
+        }
+    }
+    while let Some(&c) = stream.peek() {
+        if !is_name(c) {
+            break;
+        }
+        varname.push(c);
+        stream.next();
+    }
+    resolve(varname, env, context)
+}
+
+/// Expands the body of a `$(...)` following the opening parenthesis. Only arithmetic
+/// expansion, `$((expression))`, is currently supported.
+fn parenthesized<T: Iterator<Item=char>>(stream: &mut Peekable<T>, env: &mut Environment, context: Context, nesting: usize) -> InterpreterResult<String> {
+    let mut body = String::new();
+    let mut depth = 1;
     loop {
-        match stream.peek() {
-            None => break,
-            Some(c) if c.is_whitespace() => break,
-            Some(_) => varname.push(stream.next().unwrap())
+        match stream.next() {
+            None => return Err(InterpreterError::bad_substitution("unclosed delimiter")),
+            Some(CLOSE_PAREN) if depth == 1 => break,
+            Some(c) => {
+                match c {
+                    OPEN_PAREN => depth += 1,
+                    CLOSE_PAREN => depth -= 1,
+                    _ => {}
+                }
+                body.push(c)
+            }
+        }
+    }
+    match arithmetic_body(&body) {
+        Some(_) if nesting >= MAX_NESTING => Err(InterpreterError::bad_substitution("arithmetic expansions nested too deeply")),
+        Some(expression) => {
+            let expression: String = fragments_at(expression, env, context, nesting + 1)?.into_iter().map(|fragment| fragment.text).collect();
+            Ok(arithmetic::evaluate(expression, env)?.to_string())
         }
+        None => Err(InterpreterError::bad_substitution(format!("$({}): command substitution is not supported", body)))
+    }
+}
+
+/// Returns the expression of `(expression)` if the outer parentheses match each other.
+fn arithmetic_body(body: &str) -> Option<&str> {
+    if !body.starts_with(OPEN_PAREN) || !body.ends_with(CLOSE_PAREN) {
+        return None;
+    }
+    let mut depth = 0;
+    for (idx, c) in body.char_indices() {
+        match c {
+            OPEN_PAREN => depth += 1,
+            CLOSE_PAREN => {
+                depth -= 1;
+                if depth == 0 {
+                    return if idx == body.len() - 1 { Some(&body[1..idx]) } else { None };
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expands a parameter. One that is not set expands to nothing, unless `nounset` is on.
+fn resolve(varname: String, env: &Environment, context: Context) -> InterpreterResult<String> {
+    match lookup(&varname, env, context) {
+        Some(value) => Ok(value),
+        None if context.nounset => Err(InterpreterError::UnboundVariable{name: varname, span: None}),
+        None => Ok(String::new())
     }
-    resolve(varname, env)
 }
 
-fn resolve(varname: String, env: &Environment) -> String {
-    env.get(&varname).unwrap_or(&"".to_string()).to_string()
+/// Returns the value of a parameter, or `None` if it is not set.
+fn lookup(varname: &str, env: &Environment, context: Context) -> Option<String> {
+    match varname {
+        "@" | "*" => return Some(positional(env).join(" ")),
+        "#" => return Some(positional(env).len().to_string()),
+        "$" => return Some(std::process::id().to_string()),
+        "?" => return Some(context.status.to_string()),
+        _ => {}
+    }
+    match varname.find('[') {
+        Some(open) if varname.ends_with(']') => {
+            let name = &varname[..open];
+            match &varname[open + 1..varname.len() - 1] {
+                "@" | "*" => Some(array(name, env).join(" ")),
+                index => index.trim().parse::<usize>().ok().and_then(|index| env.get(&element(name, index))).cloned()
+            }
+        }
+        _ => env.get(varname).cloned()
+    }
+}
+
+/// Indexed arrays are stored in the environment with element zero under the array's own
+/// name, so that `$NAME` refers to it, and every other element under `NAME[index]`.
+fn element(name: &str, index: usize) -> String {
+    match index {
+        0 => name.to_string(),
+        index => format!("{}[{}]", name, index)
+    }
+}
+
+/// Returns whether an environment key is a variable name, as opposed to an array element
+/// or positional parameter, which are not exported to child processes.
+pub fn is_variable_name(key: &str) -> bool {
+    key.starts_with(is_name_start) && key.chars().all(is_name)
+}
+
+/// Returns whether an environment key holds a non-zero array element.
+fn is_element(key: &str) -> bool {
+    key.ends_with(']') && key.contains('[')
+}
+
+pub fn array(name: &str, env: &Environment) -> Vec<String> {
+    (0..).map(|index| env.get(&element(name, index))).take_while(Option::is_some).flatten().cloned().collect()
+}
+
+/// Returns the positional parameters `$1`, `$2`, ..., which are stored under their numbers.
+pub fn positional(env: &Environment) -> Vec<String> {
+    (1..).map(|index: usize| env.get(&index.to_string())).take_while(Option::is_some).flatten().cloned().collect()
+}
+
+pub fn set_positional<S: ToString>(params: &[S], env: &mut Environment) {
+    env.retain(|key, _| key == "0" || !key.chars().all(|c| c.is_ascii_digit()));
+    for (index, param) in params.iter().enumerate() {
+        env.insert((index + 1).to_string(), param.to_string());
+    }
+}
+
+pub fn set_array<S: ToString>(name: &str, values: &[S], env: &mut Environment) {
+    let prefix = format!("{}[", name);
+    env.retain(|key, _| key != name && !(key.starts_with(&prefix) && is_element(key)));
+    for (index, value) in values.iter().enumerate() {
+        env.insert(element(name, index), value.to_string());
+    }
 }
 
 #[cfg(test)]
@@ -74,7 +561,7 @@ mod tests {
     fn test_longest() {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "bob".to_string());
-        let sub = longest_match(&mut "abcd e".chars().peekable(), &env);
+        let sub = longest_match(&mut "abcd e".chars().peekable(), &env, Context::default()).unwrap();
         assert_eq!(sub, "bob".to_string())
     }
 
@@ -82,7 +569,7 @@ mod tests {
     fn test_longest_no_match() {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "b".to_string());
-        let sub = longest_match(&mut "nope".chars().peekable(), &env);
+        let sub = longest_match(&mut "nope".chars().peekable(), &env, Context::default()).unwrap();
         assert_eq!(sub, "".to_string())
     }
 
@@ -90,7 +577,7 @@ mod tests {
     fn test_longest_single() {
         let mut env = Environment::default();
         env.insert("a".to_string(), "bob".to_string());
-        let sub = longest_match(&mut "a bcd".chars().peekable(), &env);
+        let sub = longest_match(&mut "a bcd".chars().peekable(), &env, Context::default()).unwrap();
         assert_eq!(sub, "bob".to_string())
     }
 
@@ -98,7 +585,7 @@ mod tests {
     fn test_longest_contains_single() {
         let mut env = Environment::default();
         env.insert("a".to_string(), "bob".to_string());
-        let sub = longest_match(&mut "abcd a".chars().peekable(), &env);
+        let sub = longest_match(&mut "abcd a".chars().peekable(), &env, Context::default()).unwrap();
         assert_eq!(sub, "".to_string())
     }
 
@@ -107,7 +594,7 @@ mod tests {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "bob".to_string());
         env.insert("a".to_string(), "alice".to_string());
-        let sub = longest_match(&mut "abcd a".chars().peekable(), &env);
+        let sub = longest_match(&mut "abcd a".chars().peekable(), &env, Context::default()).unwrap();
         assert_eq!(sub, "bob".to_string())
     }
 
@@ -115,7 +602,7 @@ mod tests {
     fn test_delimiter() {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "bob".to_string());
-        let sub = delimited(&mut "abcd} e".chars().peekable(), &env).unwrap();
+        let sub = delimited(&mut "abcd} e".chars().peekable(), &mut env, Context::default(), 0).unwrap();
         assert_eq!(sub, "bob".to_string())
     }
 
@@ -123,7 +610,7 @@ mod tests {
     fn test_delimited_no_match() {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "b".to_string());
-        let sub = delimited(&mut "nope}".chars().peekable(), &env).unwrap();
+        let sub = delimited(&mut "nope}".chars().peekable(), &mut env, Context::default(), 0).unwrap();
         assert_eq!(sub, "".to_string())
     }
 
@@ -131,7 +618,7 @@ mod tests {
     fn test_delimited_single() {
         let mut env = Environment::default();
         env.insert("a".to_string(), "bob".to_string());
-        let sub = delimited(&mut "a} bcd".chars().peekable(), &env).unwrap();
+        let sub = delimited(&mut "a} bcd".chars().peekable(), &mut env, Context::default(), 0).unwrap();
         assert_eq!(sub, "bob".to_string())
     }
 
@@ -139,7 +626,7 @@ mod tests {
     fn test_delimited_contains_single() {
         let mut env = Environment::default();
         env.insert("a".to_string(), "bob".to_string());
-        let sub = delimited(&mut "abcd} a".chars().peekable(), &env).unwrap();
+        let sub = delimited(&mut "abcd} a".chars().peekable(), &mut env, Context::default(), 0).unwrap();
         assert_eq!(sub, "".to_string())
     }
 
@@ -148,7 +635,7 @@ mod tests {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "bob".to_string());
         env.insert("a".to_string(), "alice".to_string());
-        let sub = delimited(&mut "abcd} a".chars().peekable(), &env).unwrap();
+        let sub = delimited(&mut "abcd} a".chars().peekable(), &mut env, Context::default(), 0).unwrap();
         assert_eq!(sub, "bob".to_string())
     }
     
@@ -157,9 +644,9 @@ mod tests {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "bob".to_string());
         env.insert("a".to_string(), "alice".to_string());
-        let sub = delimited(&mut "abcd".chars().peekable(), &env);
-        if sub.is_ok() {
-            panic!(sub.unwrap())
+        let sub = delimited(&mut "abcd".chars().peekable(), &mut env, Context::default(), 0);
+        if let Ok(sub) = sub {
+            panic!("{}", sub)
         }
     }
 
@@ -168,9 +655,9 @@ mod tests {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "bob".to_string());
         env.insert("a".to_string(), "alice".to_string());
-        let sub = delimited(&mut "abc b}".chars().peekable(), &env);
-        if sub.is_ok() {
-            panic!(sub.unwrap())
+        let sub = delimited(&mut "abc b}".chars().peekable(), &mut env, Context::default(), 0);
+        if let Ok(sub) = sub {
+            panic!("{}", sub)
         }
     }
     
@@ -179,7 +666,7 @@ mod tests {
         let mut env = Environment::default();
         env.insert("abcd".to_string(), "bob".to_string());
         env.insert("a".to_string(), "alice".to_string());
-        let got= substitution("hello ${abcd}, say hello to $a".to_string(), &env).unwrap();
+        let got= substitution("hello ${abcd}, say hello to $a", &mut env, Context::default()).unwrap();
         assert_eq!("hello bob, say hello to alice".to_string(), got)
     }
 
@@ -187,7 +674,7 @@ mod tests {
     fn test_substitution_inserted() {
         let mut env = Environment::default();
         env.insert("a".to_string(), "b".to_string());
-        let got= substitution("a$a".to_string(), &env).unwrap();
+        let got= substitution("a$a", &mut env, Context::default()).unwrap();
         assert_eq!("ab".to_string(), got)
     }
 
@@ -195,7 +682,174 @@ mod tests {
     fn test_substitution_inserted_delimited() {
         let mut env = Environment::default();
         env.insert("a".to_string(), "b".to_string());
-        let got= substitution("a${a}a".to_string(), &env).unwrap();
+        let got= substitution("a${a}a", &mut env, Context::default()).unwrap();
         assert_eq!("aba".to_string(), got)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_substitution_quotes() {
+        let mut env = Environment::default();
+        env.insert("a".to_string(), "b".to_string());
+        let got = substitution("'$a' \"$a's\" \\$a", &mut env, Context::default()).unwrap();
+        assert_eq!("$a b's $a".to_string(), got)
+    }
+
+    #[test]
+    fn test_substitution_name_boundary() {
+        let mut env = Environment::default();
+        env.insert("a".to_string(), "b".to_string());
+        let got = substitution("\"$a\"/$a.txt", &mut env, Context::default()).unwrap();
+        assert_eq!("b/b.txt".to_string(), got)
+    }
+
+    #[test]
+    fn test_substitution_arithmetic() {
+        let mut env = Environment::default();
+        env.insert("x".to_string(), "4".to_string());
+        env.insert("y".to_string(), "3".to_string());
+        let got = substitution("$(( x * (2 + $y) ))-$((x++))", &mut env, Context::default()).unwrap();
+        assert_eq!("20-4".to_string(), got);
+        assert_eq!("5", env.get("x").unwrap())
+    }
+
+    #[test]
+    fn test_parameter_operators() {
+        let mut env = Environment::default();
+        env.insert("set".to_string(), "value".to_string());
+        env.insert("empty".to_string(), String::new());
+        let got = substitution("${unset:-a b}|${empty-d}|${empty:-d}|${set:+alt}|${unset+alt}|${#set}|${unset:-${set}}", &mut env, Context::default()).unwrap();
+        assert_eq!(got, "a b||d|alt||5|value");
+        assert_eq!(substitution("${new:=\"x}\"}$new", &mut env, Context::default()).unwrap(), "x}x}");
+        let nounset = Context{ nounset: true, ..Context::default() };
+        assert_eq!(substitution("${unset-}${unset:-ok}", &mut env, nounset).unwrap(), "ok");
+        assert_eq!(substitution("${unset:?is required}", &mut env, nounset).unwrap_err().to_string(), "unset: is required");
+        assert_eq!(substitution("${set%e}", &mut env, nounset).unwrap_err().to_string(), "${set%e}: bad substitution");
+        assert_eq!(substitution("${1:=x}", &mut env, nounset).unwrap_err().to_string(), "$1: cannot assign in this way");
+    }
+
+    #[test]
+    fn test_escaped_substitution() {
+        let mut env = Environment::default();
+        env.insert("a".to_string(), "*".to_string());
+        let got = escaped_substitution("$a\"$a\"'?'\\?", &mut env, Context::default(), |s| format!("<{}>", s)).unwrap();
+        assert_eq!("*<*??>".to_string(), got)
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut env = Environment::default();
+        set_array("arr", &["a", "b", "c"], &mut env);
+        let got = substitution("$arr ${arr[1]} ${arr[2]} ${arr[3]} ${arr[@]}", &mut env, Context::default()).unwrap();
+        assert_eq!("a b c  a b c".to_string(), got);
+        set_array("arr", &["z"], &mut env);
+        assert_eq!(vec!["z".to_string()], array("arr", &env));
+        assert_eq!(1, env.len())
+    }
+
+    fn split(s: &str, env: &mut Environment) -> Vec<String> {
+        fields(s, env, Context::default()).unwrap()
+    }
+
+    #[test]
+    fn test_fields_default_ifs() {
+        let mut env = Environment::default();
+        env.insert("files".to_string(), "  a b\t\nc  ".to_string());
+        env.insert("empty".to_string(), "".to_string());
+        assert_eq!(split("$files", &mut env), vec!["a", "b", "c"]);
+        assert_eq!(split("x${files}y", &mut env), vec!["x", "a", "b", "c", "y"]);
+        assert_eq!(split("\"$files\"", &mut env), vec!["  a b\t\nc  "]);
+        assert_eq!(split("$empty", &mut env), Vec::<String>::new());
+        assert_eq!(split("\"$empty\"", &mut env), vec![""]);
+        assert_eq!(split("'a b'", &mut env), vec!["a b"]);
+    }
+
+    #[test]
+    fn test_fields_custom_ifs() {
+        let mut env = Environment::default();
+        env.insert("IFS".to_string(), ": ".to_string());
+        env.insert("path".to_string(), ":a::b :c: ".to_string());
+        assert_eq!(split("$path", &mut env), vec!["", "a", "", "b", "c"]);
+        env.insert("IFS".to_string(), "".to_string());
+        assert_eq!(split("$path", &mut env), vec![":a::b :c: "]);
+    }
+
+    #[test]
+    fn test_fields_arithmetic() {
+        let mut env = Environment::default();
+        env.insert("IFS".to_string(), "0".to_string());
+        assert_eq!(split("$((101 * 10))", &mut env), vec!["1", "1"]);
+    }
+
+    #[test]
+    fn test_fields_positional() {
+        let mut env = Environment::default();
+        assert_eq!(split("\"$@\"", &mut env), Vec::<String>::new());
+        assert_eq!(split("\"x$@\"", &mut env), vec!["x"]);
+        set_positional(&["a b", "", "c"], &mut env);
+        assert_eq!(split("\"$@\"", &mut env), vec!["a b", "", "c"]);
+        assert_eq!(split("\"<$@>\"", &mut env), vec!["<a b", "", "c>"]);
+        assert_eq!(split("$@", &mut env), vec!["a", "b", "c"]);
+        assert_eq!(split("\"$*\"", &mut env), vec!["a b  c"]);
+        env.insert("IFS".to_string(), ",".to_string());
+        assert_eq!(split("\"$*\"", &mut env), vec!["a b,,c"]);
+        assert_eq!(substitution("$# ${1}", &mut env, Context::default()).unwrap(), "3 a b");
+    }
+
+    #[test]
+    fn test_tilde() {
+        let mut env = Environment::default();
+        env.insert("HOME".to_string(), "/home/it's me".to_string());
+        env.insert("PWD".to_string(), "/work".to_string());
+        env.insert("OLDPWD".to_string(), "/old".to_string());
+        assert_eq!(split("~", &mut env), vec!["/home/it's me"]);
+        assert_eq!(split("~/bin", &mut env), vec!["/home/it's me/bin"]);
+        assert_eq!(split("~+/a", &mut env), vec!["/work/a"]);
+        assert_eq!(split("~-", &mut env), vec!["/old"]);
+        assert_eq!(split("~root", &mut env), vec!["/root"]);
+        assert_eq!(split("~no_such_user_rsh/x", &mut env), vec!["~no_such_user_rsh/x"]);
+        assert_eq!(split("'~'/x", &mut env), vec!["~/x"]);
+        assert_eq!(split("\\~", &mut env), vec!["~"]);
+        assert_eq!(split("a~", &mut env), vec!["a~"]);
+        assert_eq!(split("\"~\"", &mut env), vec!["~"]);
+    }
+
+    #[test]
+    fn test_tilde_assignment() {
+        let mut env = Environment::default();
+        env.insert("HOME".to_string(), "/home/me".to_string());
+        let value = tilde("~/bin:~/.cargo/bin:'~':/usr/bin:~root", true, &env);
+        assert_eq!(substitution(value, &mut env, Context::default()).unwrap(), "/home/me/bin:/home/me/.cargo/bin:~:/usr/bin:/root");
+        assert_eq!(tilde("a:~", false, &env), "a:~");
+    }
+
+    #[test]
+    fn test_substitution_division_by_zero() {
+        let mut env = Environment::default();
+        let got = substitution("$((1 / 0))", &mut env, Context::default());
+        assert!(got.is_err())
+    }
+
+    #[test]
+    fn test_nested_arithmetic() {
+        let mut env = Environment::default();
+        let nested = |depth: usize| format!("{}1{}", "$((".repeat(depth), "))".repeat(depth));
+        assert_eq!(substitution(nested(10), &mut env, Context::default()).unwrap(), "1");
+        assert!(substitution(nested(10_000), &mut env, Context::default()).is_err());
+    }
+
+    #[test]
+    fn test_nounset() {
+        let mut env = Environment::default();
+        let nounset = Context{ nounset: true, status: 3 };
+        env.insert("-".to_string(), "u".to_string());
+        env.insert("set".to_string(), "x".to_string());
+        assert_eq!(substitution("$set ${set} $- $# $@ ${A[@]} $? ${?}", &mut env, nounset).unwrap(), "x x u 0   3 3");
+        assert_eq!(substitution("$unset", &mut env, nounset).unwrap_err().to_string(), "unset: unbound variable");
+        assert_eq!(substitution("${A[1]}", &mut env, nounset).unwrap_err().to_string(), "A[1]: unbound variable");
+        assert!(substitution("$1", &mut env, nounset).is_err());
+        // The option is the shell's, not a variable that could be changed behind its back.
+        env.remove("-");
+        assert!(substitution("$unset", &mut env, nounset).is_err());
+        assert_eq!(substitution("$unset", &mut env, Context::default()).unwrap(), "");
+    }
+}
@@ -0,0 +1,114 @@
+use crate::signals::{self, Disposition};
+use libc::c_int;
+use std::collections::BTreeMap;
+use std::io;
+
+/// What a trap is set on: a signal, or one of the conditions the shell raises itself.
+/// Conditions are ordered as `trap -p` lists them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Condition {
+    /// The shell is exiting.
+    Exit,
+    Signal(c_int),
+    /// A simple command is about to run.
+    Debug,
+    /// A command failed outside of a condition.
+    Err,
+    /// A function or sourced file has finished.
+    Return
+}
+
+impl Condition {
+    /// Parses a signal name or number, or the name of a pseudo-signal. `0` is `EXIT`.
+    pub fn parse(spec: &str) -> Option<Condition> {
+        match spec.to_ascii_uppercase().as_str() {
+            "EXIT" | "0" => Some(Condition::Exit),
+            "DEBUG" => Some(Condition::Debug),
+            "ERR" => Some(Condition::Err),
+            "RETURN" => Some(Condition::Return),
+            _ => signals::number(spec).filter(|&signal| signal != 0).map(Condition::Signal)
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Condition::Exit => "EXIT".to_string(),
+            Condition::Signal(signal) => format!("SIG{}", signals::name(*signal).unwrap_or_default()),
+            Condition::Debug => "DEBUG".to_string(),
+            Condition::Err => "ERR".to_string(),
+            Condition::Return => "RETURN".to_string()
+        }
+    }
+}
+
+/// The commands set with `trap`.
+#[derive(Default)]
+pub struct Traps {
+    actions: BTreeMap<Condition, String>
+}
+
+impl Traps {
+    /// Sets the command run on `condition`, or resets it to the default without one. An
+    /// empty command ignores a signal.
+    pub fn set(&mut self, condition: Condition, action: Option<String>) -> io::Result<()> {
+        if let Condition::Signal(signal) = condition {
+            let disposition = match action.as_deref() {
+                None => Disposition::Default,
+                Some("") => Disposition::Ignore,
+                Some(_) => Disposition::Catch
+            };
+            signals::set(signal, disposition)?;
+        }
+        match action {
+            Some(action) => self.actions.insert(condition, action),
+            None => self.actions.remove(&condition)
+        };
+        Ok(())
+    }
+
+    pub fn get(&self, condition: Condition) -> Option<&str> {
+        self.actions.get(&condition).map(String::as_str)
+    }
+
+    /// Removes the command for a condition that can only happen once, such as `EXIT`.
+    pub fn take(&mut self, condition: Condition) -> Option<String> {
+        self.actions.remove(&condition)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item=(Condition, &str)> {
+        self.actions.iter().map(|(condition, action)| (*condition, action.as_str()))
+    }
+
+    /// Takes the commands of the signals that have arrived since the last call.
+    pub fn pending(&self) -> Vec<String> {
+        signals::pending().into_iter().filter_map(|signal| self.actions.get(&Condition::Signal(signal)).cloned()).collect()
+    }
+
+    /// Forgets every trap but those that ignore signals, as a forked copy of the shell
+    /// does. The copy has already restored the signals' default dispositions.
+    pub fn reset(&mut self) {
+        self.actions.retain(|condition, action| matches!(condition, Condition::Signal(_)) && action.is_empty());
+    }
+}
+
+/// Formats the signals the way `trap -l` lists them, five to a line.
+pub fn list_signals() -> String {
+    let signals: Vec<String> = signals::list().iter().map(|(name, number)| format!("{:2}) SIG{}", number, name)).collect();
+    signals.chunks(5).map(|line| line.join("\t")).collect::<Vec<String>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Condition::parse("exit"), Some(Condition::Exit));
+        assert_eq!(Condition::parse("0"), Some(Condition::Exit));
+        assert_eq!(Condition::parse("SIGINT"), Some(Condition::Signal(libc::SIGINT)));
+        assert_eq!(Condition::parse("15").map(|condition| condition.name()), Some("SIGTERM".to_string()));
+        assert_eq!(Condition::parse("ERR"), Some(Condition::Err));
+        assert_eq!(Condition::parse("NOPE"), None);
+        assert!(list_signals().starts_with(" 1) SIGHUP\t 2) SIGINT\t 3) SIGQUIT\t 4) SIGILL\t 5) SIGTRAP\n 6) SIGABRT"));
+    }
+}